@@ -0,0 +1,225 @@
+use yasnese::nes::controller::*;
+use sdl2::keyboard::Keycode;
+use sdl2::controller::Button;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+pub const DEFAULT_CONFIG_FILE: &str = "yasnese.toml";
+
+// config key of each button, indexed by the CONTROLLER_* constants
+pub const BUTTON_NAMES: [&str; CONTROLLER_BUTTONS] = ["a", "b", "select", "start", "up", "down", "left", "right", "turbo_a", "turbo_b"];
+
+// players 3 and 4 only take part through a multitap
+pub const PLAYERS: usize = 4;
+
+const PLAYER_SECTIONS: [&str; PLAYERS] = ["player1", "player2", "player3", "player4"];
+
+// frontend settings, kept in a small TOML file:
+//
+//   [player1]
+//   a = "F"
+//   up = "Up"
+//
+//   [input]
+//   turbo_rate = 2
+//   opposing = "allow"
+//
+//   [gamepad]
+//   a = "a"
+//   stick_threshold = 16000
+//   player1 = "Xbox 360 Controller"
+//
+// key and pad button names are the SDL ones
+pub struct Config {
+    pub keys: [[Option<Keycode>; CONTROLLER_BUTTONS]; PLAYERS],
+    // frames turbo buttons stay on and off
+    pub turbo_rate: u32,
+    // "allow", "last" or "neutral" for Left+Right and Up+Down
+    pub direction_policy: DirectionPolicy,
+    // pad button for each NES button, the same for every pad
+    pub pad_buttons: [Option<Button>; CONTROLLER_BUTTONS],
+    // how far the left stick has to be pushed to press a direction, out of 32767
+    pub stick_threshold: i16,
+    // preferred pad for each player, by name; other pads take the first free player
+    pub pad_names: [Option<String>; PLAYERS]
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let mut keys = [[None; CONTROLLER_BUTTONS]; PLAYERS];
+        keys[0][CONTROLLER_A] = Some(Keycode::F);
+        keys[0][CONTROLLER_B] = Some(Keycode::D);
+        keys[0][CONTROLLER_SELECT] = Some(Keycode::LAlt);
+        keys[0][CONTROLLER_START] = Some(Keycode::LCtrl);
+        keys[0][CONTROLLER_UP] = Some(Keycode::Up);
+        keys[0][CONTROLLER_DOWN] = Some(Keycode::Down);
+        keys[0][CONTROLLER_LEFT] = Some(Keycode::Left);
+        keys[0][CONTROLLER_RIGHT] = Some(Keycode::Right);
+        keys[0][CONTROLLER_TURBO_A] = Some(Keycode::G);
+        keys[0][CONTROLLER_TURBO_B] = Some(Keycode::S);
+        keys[1][CONTROLLER_A] = Some(Keycode::M);
+        keys[1][CONTROLLER_B] = Some(Keycode::N);
+        keys[1][CONTROLLER_SELECT] = Some(Keycode::RShift);
+        keys[1][CONTROLLER_START] = Some(Keycode::Return);
+        keys[1][CONTROLLER_UP] = Some(Keycode::I);
+        keys[1][CONTROLLER_DOWN] = Some(Keycode::K);
+        keys[1][CONTROLLER_LEFT] = Some(Keycode::J);
+        keys[1][CONTROLLER_RIGHT] = Some(Keycode::L);
+        let mut pad_buttons = [None; CONTROLLER_BUTTONS];
+        pad_buttons[CONTROLLER_A] = Some(Button::A);
+        pad_buttons[CONTROLLER_B] = Some(Button::X);
+        pad_buttons[CONTROLLER_SELECT] = Some(Button::Back);
+        pad_buttons[CONTROLLER_START] = Some(Button::Start);
+        pad_buttons[CONTROLLER_UP] = Some(Button::DPadUp);
+        pad_buttons[CONTROLLER_DOWN] = Some(Button::DPadDown);
+        pad_buttons[CONTROLLER_LEFT] = Some(Button::DPadLeft);
+        pad_buttons[CONTROLLER_RIGHT] = Some(Button::DPadRight);
+        pad_buttons[CONTROLLER_TURBO_A] = Some(Button::B);
+        pad_buttons[CONTROLLER_TURBO_B] = Some(Button::Y);
+        Config {
+            keys,
+            turbo_rate: 2,
+            direction_policy: DirectionPolicy::Allow,
+            pad_buttons,
+            stick_threshold: 16000,
+            pad_names: [None, None, None, None]
+        }
+    }
+}
+
+// "value" -> value, bare values are accepted too
+fn unquote(value: &str) -> &str {
+    value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value)
+}
+
+impl Config {
+    // defaults when the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        Config::parse(&fs::read_to_string(path)?)
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(text: &str) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        let mut section = String::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("line {}: expected key = value", n + 1))?;
+            config.set(&section, key.trim(), unquote(value.trim()))
+                .map_err(|e| format!("line {}: {}", n + 1, e))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(player) = PLAYER_SECTIONS.iter().position(|&s| s == section) {
+            let button = BUTTON_NAMES.iter().position(|&b| b == key)
+                .ok_or_else(|| format!("unknown button {}", key))?;
+            self.keys[player][button] = if value.is_empty() {
+                None
+            }
+            else {
+                Some(Keycode::from_name(value).ok_or_else(|| format!("unknown key {}", value))?)
+            };
+            return Ok(());
+        }
+        if section == "input" {
+            match key {
+                "turbo_rate" => self.turbo_rate = value.parse()?,
+                "opposing" => {
+                    self.direction_policy = match value {
+                        "allow" => DirectionPolicy::Allow,
+                        "last" => DirectionPolicy::LastPressed,
+                        "neutral" => DirectionPolicy::Neutralize,
+                        _ => return Err(format!("invalid opposing policy {}", value).into())
+                    };
+                },
+                _ => return Err(format!("unknown setting [{}] {}", section, key).into())
+            }
+            return Ok(());
+        }
+        if section == "gamepad" {
+            if let Some(button) = BUTTON_NAMES.iter().position(|&b| b == key) {
+                self.pad_buttons[button] = if value.is_empty() {
+                    None
+                }
+                else {
+                    Some(Button::from_string(value).ok_or_else(|| format!("unknown pad button {}", value))?)
+                };
+                return Ok(());
+            }
+            match key {
+                "stick_threshold" => {
+                    let threshold: i32 = value.parse()?;
+                    if !(0..=i16::MAX as i32).contains(&threshold) {
+                        return Err(format!("stick_threshold must be between 0 and 32767, not {}", threshold).into());
+                    }
+                    self.stick_threshold = threshold as i16;
+                },
+                _ => {
+                    let player = PLAYER_SECTIONS.iter().position(|&s| s == key)
+                        .ok_or_else(|| format!("unknown setting [{}] {}", section, key))?;
+                    self.pad_names[player] = Some(value.to_string()).filter(|v| !v.is_empty());
+                }
+            }
+            return Ok(());
+        }
+        Err(format!("unknown setting [{}] {}", section, key).into())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut text = String::new();
+        for (player, section) in PLAYER_SECTIONS.iter().enumerate() {
+            text += &format!("[{}]\n", section);
+            for (button, name) in BUTTON_NAMES.iter().enumerate() {
+                let key = self.keys[player][button].map_or(String::new(), |k| k.name());
+                text += &format!("{} = \"{}\"\n", name, key);
+            }
+            text += "\n";
+        }
+        let opposing = match self.direction_policy {
+            DirectionPolicy::Allow => "allow",
+            DirectionPolicy::LastPressed => "last",
+            DirectionPolicy::Neutralize => "neutral"
+        };
+        text += &format!("[input]\nturbo_rate = {}\nopposing = \"{}\"\n\n", self.turbo_rate, opposing);
+        text += "[gamepad]\n";
+        for (button, name) in BUTTON_NAMES.iter().enumerate() {
+            let pad_button = self.pad_buttons[button].map_or(String::new(), |b| b.string());
+            text += &format!("{} = \"{}\"\n", name, pad_button);
+        }
+        text += &format!("stick_threshold = {}\n", self.stick_threshold);
+        for (player, name) in self.pad_names.iter().enumerate() {
+            text += &format!("player{} = \"{}\"\n", player + 1, name.as_deref().unwrap_or(""));
+        }
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    // binds a key to a player's button, taking it away from whatever used it before
+    pub fn bind_key(&mut self, player: usize, button: usize, keycode: Keycode) {
+        for key in self.keys.iter_mut().flatten() {
+            if *key == Some(keycode) {
+                *key = None;
+            }
+        }
+        self.keys[player][button] = Some(keycode);
+    }
+
+    // the player and button a key is bound to
+    pub fn key_binding(&self, keycode: Keycode) -> Option<(usize, usize)> {
+        (0..PLAYERS).flat_map(|player| (0..CONTROLLER_BUTTONS).map(move |button| (player, button)))
+            .find(|&(player, button)| self.keys[player][button] == Some(keycode))
+    }
+}
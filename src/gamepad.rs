@@ -0,0 +1,86 @@
+use crate::config::{Config, PLAYERS};
+use yasnese::nes::controller::*;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::GameControllerSubsystem;
+
+// SDL game controllers, each driving one player's pad
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    // open pads with the player they drive
+    pads: Vec<(GameController, usize)>,
+    // players that can be driven: 2, or 4 with a multitap
+    players: usize
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem, players: usize) -> Gamepads {
+        Gamepads {
+            subsystem,
+            pads: Vec::new(),
+            players: std::cmp::min(players, PLAYERS)
+        }
+    }
+
+    // opens the pad at the given joystick index, on hotplug or at startup, and returns
+    // the player it was given
+    pub fn add(&mut self, index: u32, config: &Config) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        if !self.subsystem.is_game_controller(index) {
+            return Ok(None);
+        }
+        let pad = self.subsystem.open(index)?;
+        if self.pads.iter().any(|(p, _)| p.instance_id() == pad.instance_id()) {
+            return Ok(None);
+        }
+        let name = pad.name();
+        let taken: Vec<usize> = self.pads.iter().map(|&(_, player)| player).collect();
+        let free = |player: &usize| !taken.contains(player);
+        let player = (0..self.players).filter(free).find(|&p| config.pad_names[p].as_deref() == Some(name.as_str()))
+            .or_else(|| (0..self.players).find(free));
+        match player {
+            Some(player) => {
+                self.pads.push((pad, player));
+                Ok(Some(player))
+            },
+            // every player has a pad already
+            None => Ok(None)
+        }
+    }
+
+    // forgets an unplugged pad, returning its player so its buttons can be released
+    pub fn remove(&mut self, instance_id: i32) -> Option<usize> {
+        let pos = self.pads.iter().position(|(p, _)| p.instance_id() == instance_id)?;
+        let (_, player) = self.pads.remove(pos);
+        Some(player)
+    }
+
+    fn player(&self, instance_id: i32) -> Option<usize> {
+        self.pads.iter().find(|(p, _)| p.instance_id() == instance_id).map(|&(_, player)| player)
+    }
+
+    // NES buttons a pad button event maps to, as (player, button, pressed)
+    pub fn button(&self, instance_id: i32, button: Button, pressed: bool, config: &Config) -> Vec<(usize, usize, bool)> {
+        let player = match self.player(instance_id) {
+            Some(player) => player,
+            None => return Vec::new()
+        };
+        (0..CONTROLLER_BUTTONS)
+            .filter(|&nes_button| config.pad_buttons[nes_button] == Some(button))
+            .map(|nes_button| (player, nes_button, pressed))
+            .collect()
+    }
+
+    // same for left stick motion, past the threshold the stick acts as the d-pad
+    pub fn axis(&self, instance_id: i32, axis: Axis, value: i16, config: &Config) -> Vec<(usize, usize, bool)> {
+        let player = match self.player(instance_id) {
+            Some(player) => player,
+            None => return Vec::new()
+        };
+        let (negative, positive) = match axis {
+            Axis::LeftX => (CONTROLLER_LEFT, CONTROLLER_RIGHT),
+            Axis::LeftY => (CONTROLLER_UP, CONTROLLER_DOWN),
+            _ => return Vec::new()
+        };
+        let threshold = config.stick_threshold;
+        vec![(player, negative, value < -threshold), (player, positive, value > threshold)]
+    }
+}
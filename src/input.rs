@@ -0,0 +1,49 @@
+use crate::config::PLAYERS;
+use yasnese::nes;
+use yasnese::nes::controller::CONTROLLER_BUTTONS;
+
+// where a button press comes from
+#[derive(Clone, Copy)]
+pub enum Source {
+    Keyboard,
+    Pad,
+    Stick
+}
+
+const SOURCES: usize = 3;
+
+// buttons held by each source, per player; the NES sees a button as pressed while
+// any source holds it, so that releasing a pad button doesn't cancel the keyboard
+pub struct Input {
+    held: [[[bool; CONTROLLER_BUTTONS]; PLAYERS]; SOURCES]
+}
+
+impl Input {
+    pub fn new() -> Input {
+        Input {
+            held: [[[false; CONTROLLER_BUTTONS]; PLAYERS]; SOURCES]
+        }
+    }
+
+    fn pressed(&self, player: usize, button: usize) -> bool {
+        self.held.iter().any(|source| source[player][button])
+    }
+
+    // records the state of a button for one source, passing it on to the NES when the
+    // combined state changes
+    pub fn set(&mut self, nes: &mut nes::Nes, source: Source, player: usize, button: usize, pressed: bool) {
+        let was_pressed = self.pressed(player, button);
+        self.held[source as usize][player][button] = pressed;
+        let is_pressed = self.pressed(player, button);
+        if is_pressed != was_pressed {
+            nes.set_button(player, button, is_pressed);
+        }
+    }
+
+    // lets go of everything a source holds for a player, e.g. when a pad is unplugged
+    pub fn release(&mut self, nes: &mut nes::Nes, source: Source, player: usize) {
+        for button in 0..CONTROLLER_BUTTONS {
+            self.set(nes, source, player, button, false);
+        }
+    }
+}
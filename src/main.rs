@@ -1,29 +1,192 @@
-mod nes;
+mod config;
+mod gamepad;
+mod input;
+
+use yasnese::nes;
 use std::io::prelude::*;
 use std::env;
 use std::error::Error;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
 
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use sdl2::audio::AudioSpecDesired;
 use std::time::SystemTime;
+use std::path::{Path, PathBuf};
+
+const WINDOW_TITLE: &str = "yasnese v0.1";
+
+// flush battery RAM every ~10 seconds, besides on exit
+const SAVE_FLUSH_FRAMES: u64 = 600;
+
+// emulator hotkeys, handled before the key bindings so they can't be bound
+const RESERVED_KEYS: [Keycode; 8] = [
+    Keycode::R, Keycode::Space, Keycode::Tab, Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4, Keycode::Escape
+];
+
+fn rebind_title(player: usize, button: usize) -> String {
+    format!("yasnese - player {} {}: press a key, Esc to stop", player + 1, config::BUTTON_NAMES[button])
+}
+
+// mouse coordinates are already scaled to the 256x240 logical size
+fn screen_position(x: i32, y: i32) -> Option<(u32, u32)> {
+    if (0..256).contains(&x) && (0..240).contains(&y) { Some((x as u32, y as u32)) } else { None }
+}
+
+// flushes battery RAM when the main loop is left, on errors too
+struct SaveGuard<'a>(&'a mut nes::Nes);
+
+impl Drop for SaveGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.flush_battery_ram() {
+            println!("failed to save battery RAM: {}", e);
+        }
+    }
+}
+
+impl Deref for SaveGuard<'_> {
+    type Target = nes::Nes;
+
+    fn deref(&self) -> &nes::Nes {
+        self.0
+    }
+}
+
+impl DerefMut for SaveGuard<'_> {
+    fn deref_mut(&mut self) -> &mut nes::Nes {
+        self.0
+    }
+}
+
+fn kb(size: usize) -> String {
+    if size % 1024 == 0 { format!("{}K", size / 1024) } else { format!("{} bytes", size) }
+}
+
+// prints what the loader makes of a ROM file, without running it
+fn print_rom_info(romfile: &str, registry: &nes::mapper::MapperRegistry, gamedb: Option<&nes::gamedb::GameDb>) -> Result<(), Box<dyn Error>> {
+    let data = std::fs::read(romfile)?;
+    if nes::fds::is_fds_image(&data) {
+        println!("  format:     FDS disk image");
+        return Ok(());
+    }
+
+    let rom = nes::rom::NesRom::from_bytes_with_db(&data, gamedb)?;
+    println!("  format:     {:?}", rom.format);
+    if let Some(board) = &rom.board {
+        println!("  board:      {}", board);
+    }
+    let name = registry.info(rom.mapper_id, rom.submapper)
+        .map_or(String::from("unknown"), |info| format!("{}, {} board", info.name, info.board));
+    println!("  mapper:     {}.{} ({})", rom.mapper_id, rom.submapper, name);
+    println!("  supported:  {}", if registry.is_supported(rom.mapper_id, rom.submapper) { "yes" } else { "no" });
+    println!("  PRG-ROM:    {}", kb(rom.prg_rom.len()));
+    println!("  CHR-ROM:    {}", kb(rom.chr_rom.len()));
+    println!("  CHR-RAM:    {}", kb(rom.chr_ram.len()));
+    println!("  PRG-RAM:    {}", kb(rom.prg_ram_size));
+    println!("  PRG-NVRAM:  {}", kb(rom.prg_nvram_size));
+    println!("  battery:    {}", if rom.has_battery() { "yes" } else { "no" });
+    println!("  trainer:    {}", if rom.trainer.is_some() { "yes" } else { "no" });
+    println!("  mirroring:  {:?}", rom.mirroring);
+    println!("  region:     {:?}", rom.timing);
+    println!("  console:    {:?}", rom.console_type);
+    println!("  CRC32:      {:08X}", rom.crc32);
+    println!("  SHA-1:      {}", nes::checksum::to_hex(&rom.sha1));
+    println!("  database:   {}", if rom.in_database { "match, header corrected" } else { "no match" });
+    Ok(())
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
 
-    let mut nes = if args.len() > 1 {
-        nes::Nes::new(&args[1])?
+    // yasnese info [--gamedb <file>] <rom>...
+    if args.len() > 1 && args[1] == "info" {
+        let (gamedb, romfiles) = if args.len() > 3 && args[2] == "--gamedb" {
+            (Some(nes::gamedb::GameDb::from_file(Path::new(&args[3]))?), &args[4..])
+        }
+        else {
+            (None, &args[2..])
+        };
+        if romfiles.is_empty() {
+            return Err("usage: yasnese info [--gamedb <file>] <rom>...".into());
+        }
+        let registry = nes::mapper::MapperRegistry::new();
+        for romfile in romfiles {
+            println!("{}", romfile);
+            if let Err(e) = print_rom_info(romfile, &registry, gamedb.as_ref()) {
+                println!("  error:      {}", e);
+            }
+        }
+        return Ok(());
     }
-    else {
-        nes::Nes::new("roms/super_mario_bros_u.nes")?
-    };
+
+    let mut romfile = "roms/super_mario_bros_u.nes";
+    let mut save_dir = None;
+    let mut options = nes::LoadOptions::default();
+    let mut config_path = PathBuf::from(config::DEFAULT_CONFIG_FILE);
+    let mut use_zapper = false;
+    let mut multitap = None;
+    let mut arg = args.iter().skip(1);
+    while let Some(a) = arg.next() {
+        if a == "--save-dir" {
+            save_dir = Some(arg.next().ok_or("--save-dir needs a directory")?);
+        }
+        else if a == "--patch" {
+            options.patch = Some(PathBuf::from(arg.next().ok_or("--patch needs a file")?));
+        }
+        else if a == "--config" {
+            config_path = PathBuf::from(arg.next().ok_or("--config needs a file")?);
+        }
+        else if a == "--fourscore" {
+            multitap = Some(nes::controller::MultitapKind::FourScore);
+        }
+        else if a == "--hori" {
+            multitap = Some(nes::controller::MultitapKind::Hori);
+        }
+        else if a == "--zapper" {
+            use_zapper = true;
+        }
+        else if a == "--gamedb" {
+            options.gamedb = Some(PathBuf::from(arg.next().ok_or("--gamedb needs a file")?));
+        }
+        else if a == "--bios" {
+            options.fds_bios = Some(PathBuf::from(arg.next().ok_or("--bios needs a file")?));
+        }
+        else {
+            romfile = a;
+        }
+    }
+
+    // the Zapper takes port 2, which the multitap needs for players 2 and 4
+    if use_zapper && multitap.is_some() {
+        return Err("--zapper can't be combined with --fourscore or --hori".into());
+    }
+
+    let mut nes = nes::Nes::with_options(romfile, &options, &nes::mapper::MapperRegistry::new())?;
+    if let Some(kind) = multitap {
+        nes.connect_multitap(kind);
+    }
+    // the mouse aims, left button fires, right button fires away from the screen
+    let zapper = if use_zapper { Some(nes.connect_zapper()) } else { None };
+    let mut config = config::Config::load(&config_path)?;
+    nes.set_turbo_rate(config.turbo_rate);
+    nes.set_direction_policy(config.direction_policy);
+    if let Some(dir) = save_dir {
+        nes.set_save_dir(Path::new(dir))?;
+    }
+
+    // player and next button being rebound from the keyboard, F1-F4 start it
+    let mut rebind: Option<(usize, usize)> = None;
 
     let sdl_ctx = sdl2::init()?;
     let video = sdl_ctx.video()?;
     let audio = sdl_ctx.audio()?;
+    // pads present at startup are reported through ControllerDeviceAdded as well
+    let mut gamepads = gamepad::Gamepads::new(sdl_ctx.game_controller()?, if multitap.is_some() { 4 } else { 2 });
+    let mut input = input::Input::new();
 
     let audio_spec = AudioSpecDesired {
         freq: Some(44100),
@@ -34,7 +197,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let audio_queue = Rc::new(RefCell::new(audio.open_queue::<u8, _>(None, &audio_spec)?));
     audio_queue.borrow().clear();
 
-    let window = video.window("yasnese v0.1", 256*4, 240*4)
+    let window = video.window(WINDOW_TITLE, 256*4, 240*4)
         .position_centered()
         .build()
         .unwrap();
@@ -56,12 +219,38 @@ fn main() -> Result<(), Box<dyn Error>> {
     nes.powerup();
     nes.reset();
     nes.set_audio_queue(Rc::clone(&audio_queue));
-//    audio_queue.borrow().resume();
+    audio_queue.borrow().resume();
 
+    let mut nes = SaveGuard(&mut nes);
     let mut frame_count = 0;
     let mut t = SystemTime::now();
     'running: loop {
         for event in event_pump.poll_iter() {
+            if let (Some((player, button)), Event::KeyDown { keycode: Some(k), .. }) = (rebind, &event) {
+                if *k != Keycode::Escape && RESERVED_KEYS.contains(k) {
+                    // keep waiting for a usable key
+                    continue;
+                }
+                if *k != Keycode::Escape {
+                    config.bind_key(player, button, *k);
+                }
+                rebind = if *k != Keycode::Escape && button + 1 < config::BUTTON_NAMES.len() {
+                    Some((player, button + 1))
+                }
+                else {
+                    None
+                };
+                match rebind {
+                    Some((player, button)) => {
+                        canvas.window_mut().set_title(&rebind_title(player, button))?;
+                    },
+                    None => {
+                        config.save(&config_path)?;
+                        canvas.window_mut().set_title(WINDOW_TITLE)?;
+                    }
+                }
+                continue;
+            }
             match event {
                 Event::Quit { .. } |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
@@ -73,11 +262,82 @@ fn main() -> Result<(), Box<dyn Error>> {
                 Event::KeyDown { keycode: Some(Keycode::Space), ..} => {
                     pause = !pause;
                 },
-                Event::KeyDown { keycode: k @ Some(_), .. } => {
-                    nes.update_controller(k.unwrap(), true);
+                Event::KeyDown { keycode: Some(Keycode::Tab), ..} => {
+                    // flip to the next disk side, FDS only
+                    let sides = nes.disk_sides();
+                    if sides > 0 {
+                        let next = nes.inserted_disk().map_or(0, |side| (side + 1) % sides);
+                        nes.insert_disk(Some(next));
+                    }
+                },
+                Event::KeyDown { keycode: Some(k @ Keycode::F1), .. } |
+                Event::KeyDown { keycode: Some(k @ Keycode::F2), .. } |
+                Event::KeyDown { keycode: Some(k @ Keycode::F3), .. } |
+                Event::KeyDown { keycode: Some(k @ Keycode::F4), .. } => {
+                    let player = [Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4].iter().position(|&f| f == k).unwrap();
+                    rebind = Some((player, 0));
+                    canvas.window_mut().set_title(&rebind_title(player, 0))?;
+                },
+                Event::KeyDown { keycode: Some(k), .. } => {
+                    if let Some((player, button)) = config.key_binding(k) {
+                        input.set(&mut nes, input::Source::Keyboard, player, button, true);
+                    }
+                },
+                Event::KeyUp { keycode: Some(k), .. } => {
+                    if let Some((player, button)) = config.key_binding(k) {
+                        input.set(&mut nes, input::Source::Keyboard, player, button, false);
+                    }
                 },
-                Event::KeyUp { keycode: k @ Some(_), .. } => {
-                    nes.update_controller(k.unwrap(), false);
+                Event::MouseMotion { x, y, .. } => {
+                    if let Some(zapper) = &zapper {
+                        zapper.borrow_mut().set_position(screen_position(x, y));
+                    }
+                },
+                Event::MouseButtonDown { mouse_btn, x, y, .. } => {
+                    if let Some(zapper) = &zapper {
+                        let mut zapper = zapper.borrow_mut();
+                        if mouse_btn == MouseButton::Right {
+                            zapper.set_position(None);
+                        }
+                        else {
+                            zapper.set_position(screen_position(x, y));
+                        }
+                        zapper.set_trigger(true);
+                    }
+                },
+                Event::MouseButtonUp { .. } => {
+                    if let Some(zapper) = &zapper {
+                        zapper.borrow_mut().set_trigger(false);
+                    }
+                },
+                Event::ControllerDeviceAdded { which, .. } => {
+                    // a pad that fails to open is skipped, the keyboard still works
+                    match gamepads.add(which, &config) {
+                        Ok(Some(player)) => println!("gamepad {} assigned to player {}", which, player + 1),
+                        Ok(None) => {},
+                        Err(e) => println!("gamepad {}: {}", which, e)
+                    }
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(player) = gamepads.remove(which) {
+                        input.release(&mut nes, input::Source::Pad, player);
+                        input.release(&mut nes, input::Source::Stick, player);
+                    }
+                },
+                Event::ControllerButtonDown { which, button, .. } => {
+                    for (player, button, pressed) in gamepads.button(which, button, true, &config) {
+                        input.set(&mut nes, input::Source::Pad, player, button, pressed);
+                    }
+                },
+                Event::ControllerButtonUp { which, button, .. } => {
+                    for (player, button, pressed) in gamepads.button(which, button, false, &config) {
+                        input.set(&mut nes, input::Source::Pad, player, button, pressed);
+                    }
+                },
+                Event::ControllerAxisMotion { which, axis, value, .. } => {
+                    for (player, button, pressed) in gamepads.axis(which, axis, value, &config) {
+                        input.set(&mut nes, input::Source::Stick, player, button, pressed);
+                    }
                 }
                 _ => {}
             }
@@ -92,6 +352,10 @@ fn main() -> Result<(), Box<dyn Error>> {
         canvas.copy(&texture, None, None)?;
         canvas.present();
         frame_count += 1;
+        if frame_count % SAVE_FLUSH_FRAMES == 0 {
+            nes.flush_battery_ram()?;
+        }
     }
+    nes.flush_battery_ram()?;
     Ok(())
 }
@@ -6,6 +6,11 @@ use std::option::Option;
 const APU_STATUS: u16 = 0x4015;
 const APU_FRAME_COUNTER: u16 = 0x4017;
 
+// NTSC CPU clock over the output sample rate
+const CYCLES_PER_SAMPLE: f64 = 1_789_773.0 / 44_100.0;
+// bytes of queued audio (about 50ms) beyond which new samples are dropped
+const AUDIO_QUEUE_LIMIT: usize = 735 * 3;
+
 const APU_STATUS_PULSE1: u8 = 1 << 0;
 const APU_STATUS_PULSE2: u8 = 1 << 1;
 const APU_STATUS_TRIANGLE: u8 = 1 << 2;
@@ -31,7 +36,7 @@ const APU_PULSE_DUTY: [[u8; 8]; 4] = [
 const APU_SEQUENCER_MODE0: [u16; 4] = [7457, 14913, 22371, 29828];
 const APU_SEQUENCER_MODE1: [u16; 5] = [7457, 14913, 22371, 29829, 37281];
 
-struct Pulse {
+pub struct Pulse {
     enabled: bool,
     duty_table: usize,
     decay_loop: bool,
@@ -192,7 +197,11 @@ impl Pulse {
         }
     }
 
-    pub fn out(&mut self) -> u8 {
+    pub fn length_active(&self) -> bool {
+        self.length_counter != 0
+    }
+
+    pub fn out(&self) -> u8 {
         if APU_PULSE_DUTY[self.duty_table][self.duty_counter as usize] == 1 && self.length_counter != 0 && !self.sweep_silence() {
             return if self.decay_enabled { self.decay_hidden_v } else { self.decay_v };
         }
@@ -250,7 +259,8 @@ pub struct Apu {
     accum: f64,
     out_buf: [u8; 735],
     out_index: usize,
-    audio_ready: bool
+    audio_ready: bool,
+    expansion: u8
 }
 
 impl Apu {
@@ -269,7 +279,8 @@ impl Apu {
             accum: 0.0,
             out_buf: [0; 735],
             out_index: 0,
-            audio_ready: false
+            audio_ready: false,
+            expansion: 0
         }
     }
 
@@ -394,13 +405,16 @@ impl Apu {
 
     fn step(&mut self) {
         self.cycle += 1;
-        if self.cycle % 2 == 0 {
+        if self.cycle.is_multiple_of(2) {
             self.apu_cycle += 1;
             self.pulse1.step();
             self.pulse2.step();
         }
         self.sequencer();
-        if self.cycle % 40 == 0 {
+        // CPU cycles don't divide evenly into samples, carry the remainder over
+        self.accum += 1.0;
+        if self.accum >= CYCLES_PER_SAMPLE {
+            self.accum -= CYCLES_PER_SAMPLE;
             let mut sample: u8 = 0;
             if self.pulse1.enabled {
                 sample = self.pulse1.out();
@@ -408,12 +422,16 @@ impl Apu {
             if self.pulse2.enabled {
                 sample += self.pulse2.out();
             }
+            sample = sample.saturating_add(self.expansion);
             self.out_buf[self.out_index] = sample;
             self.out_index += 1;
             if self.out_index == self.out_buf.len() {
                 if let Some(x) = &mut self.audio_queue {
                     let x = x.borrow();
-                    x.queue(&self.out_buf);
+                    // drop the buffer rather than let latency build up when running ahead
+                    if (x.size() as usize) < AUDIO_QUEUE_LIMIT {
+                        x.queue(&self.out_buf);
+                    }
                 }
                 self.out_index = 0;
                 self.audio_ready = true;
@@ -421,6 +439,11 @@ impl Apu {
         }
     }
 
+    // current output level of the cartridge audio chip, if any
+    pub fn set_expansion_output(&mut self, value: u8) {
+        self.expansion = value;
+    }
+
     pub fn set_audio_queue(&mut self, audio_queue: Rc<RefCell<AudioQueue<u8>>>) {
         self.audio_queue = Some(Rc::clone(&audio_queue));
     }
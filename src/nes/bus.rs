@@ -10,7 +10,8 @@ pub struct Bus {
     pub mapper: Rc<RefCell<dyn mapper::Mapper>>,
     pub ppu: ppu::Ppu,
     pub apu: apu::Apu,
-    pub controller: controller::Controller
+    // devices plugged into the two controller ports, read at $4016 and $4017
+    pub ports: [Option<Rc<RefCell<dyn controller::InputDevice>>>; 2]
 }
 
 impl Bus {
@@ -20,7 +21,7 @@ impl Bus {
             mapper: Rc::clone(&mapper),
             ppu,
             apu,
-            controller: controller::Controller::new()
+            ports: [None, None]
         }
     }
 
@@ -31,14 +32,22 @@ impl Bus {
         else if address < 0x4000 {
             return self.ppu.read_reg(address % 0x08);
         }
-        else if address == 0x4016 {
-            self.controller.load_u8()
+        else if address == 0x4016 || address == 0x4017 {
+            match &self.ports[(address & 1) as usize] {
+                Some(device) => {
+                    let mut device = device.borrow_mut();
+                    let (scanline, dot) = self.ppu.beam_position();
+                    device.sense_light(self.ppu.framebuffer(), scanline, dot);
+                    device.load_u8()
+                },
+                None => 0
+            }
         }
         else if address < 0x4020 {
             return 0;
         }
         else {
-            return self.mapper.borrow().load_prg_u8(address);
+            return self.mapper.borrow_mut().load_prg_u8(address);
         }
     }
 
@@ -52,6 +61,7 @@ impl Bus {
         }
         else if address < 0x4000 {
             self.ppu.write_reg(address % 0x08, value);
+            self.mapper.borrow_mut().ppu_register_write(address % 0x08, value);
         }
         else if address == 0x4000 {
             self.apu.write4000(value);
@@ -69,7 +79,9 @@ impl Bus {
             self.apu.write4015(value);
         }
         else if address == 0x4016 {
-            self.controller.store_u8(value);
+            for device in self.ports.iter().flatten() {
+                device.borrow_mut().store_u8(value);
+            }
         }
         else if address == 0x4017 {
             self.apu.write4017(value);
@@ -90,6 +102,10 @@ impl Bus {
         self.ppu.pending_nmi()
     }
 
+    pub fn pending_irq(&self) -> bool {
+        self.mapper.borrow().irq()
+    }
+
     pub fn reset(&mut self) {
         self.ppu.reset();
         self.apu.reset();
@@ -97,6 +113,9 @@ impl Bus {
 
     pub fn run(&mut self, cycles: u64) {
         self.ppu.run(cycles*3);
-//        self.apu.run(cycles);
+        self.mapper.borrow_mut().run(cycles);
+        // cartridge audio is mixed into the APU samples
+        self.apu.set_expansion_output(self.mapper.borrow().audio_out());
+        self.apu.run(cycles);
     }
 }
\ No newline at end of file
@@ -1,76 +1,318 @@
-use sdl2::keyboard::Keycode;
+use std::rc::Rc;
+use std::cell::RefCell;
 
-const CONTROLLER_A: usize = 0;
-const CONTROLLER_B: usize = 1;
-const CONTROLLER_SELECT: usize = 2;
-const CONTROLLER_START: usize = 3;
-const CONTROLLER_UP: usize = 4;
-const CONTROLLER_DOWN: usize = 5;
-const CONTROLLER_LEFT: usize = 6;
-const CONTROLLER_RIGHT: usize = 7;
+pub const CONTROLLER_A: usize = 0;
+pub const CONTROLLER_B: usize = 1;
+pub const CONTROLLER_SELECT: usize = 2;
+pub const CONTROLLER_START: usize = 3;
+pub const CONTROLLER_UP: usize = 4;
+pub const CONTROLLER_DOWN: usize = 5;
+pub const CONTROLLER_LEFT: usize = 6;
+pub const CONTROLLER_RIGHT: usize = 7;
+// turbo buttons, pressing A/B on and off at the turbo rate while held
+pub const CONTROLLER_TURBO_A: usize = 8;
+pub const CONTROLLER_TURBO_B: usize = 9;
+pub const CONTROLLER_BUTTONS: usize = 10;
 
+// what the game sees when both directions of a pair are held, which a real d-pad
+// can't do and some games crash on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DirectionPolicy {
+    Allow,
+    LastPressed,
+    Neutralize
+}
+
+// anything plugged into one of the two controller ports
+pub trait InputDevice {
+    // CPU write to $4016, the strobe line is shared by both ports
+    fn store_u8(&mut self, value: u8);
+    // CPU read from $4016 (port 1) or $4017 (port 2)
+    fn load_u8(&mut self) -> u8;
+
+    // called before each read with the PPU picture (256x240 BGRA) and beam position,
+    // for light guns
+    fn sense_light(&mut self, _framebuffer: &[u8], _scanline: u32, _dot: u32) {}
+}
+
+// standard NES pad, an 8 bit shift register
 pub struct Controller {
-    buttons: [bool; 8],
+    buttons: [bool; CONTROLLER_BUTTONS],
     strobe: bool,
-    index: usize
+    index: usize,
+    // frames turbo buttons stay pressed, then released
+    turbo_rate: u32,
+    frame: u32,
+    policy: DirectionPolicy,
+    // the direction pressed last in each pair, for DirectionPolicy::LastPressed
+    last_horizontal: usize,
+    last_vertical: usize
 }
 
 impl Controller {
     pub fn new() -> Controller {
         Controller {
-            buttons: [false; 8],
+            buttons: [false; CONTROLLER_BUTTONS],
             strobe: false,
-            index: 0
+            index: 0,
+            turbo_rate: 2,
+            frame: 0,
+            policy: DirectionPolicy::Allow,
+            last_horizontal: CONTROLLER_RIGHT,
+            last_vertical: CONTROLLER_DOWN
+        }
+    }
+
+    pub fn set_button(&mut self, button: usize, pressed: bool) {
+        self.buttons[button] = pressed;
+        if pressed {
+            match button {
+                CONTROLLER_LEFT | CONTROLLER_RIGHT => self.last_horizontal = button,
+                CONTROLLER_UP | CONTROLLER_DOWN => self.last_vertical = button,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn set_turbo_rate(&mut self, frames: u32) {
+        self.turbo_rate = std::cmp::max(frames, 1);
+    }
+
+    pub fn set_direction_policy(&mut self, policy: DirectionPolicy) {
+        self.policy = policy;
+    }
+
+    // called once per video frame, to time turbo
+    pub fn next_frame(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    fn direction(&self, button: usize, opposite: usize, last: usize) -> bool {
+        if !self.buttons[opposite] {
+            return self.buttons[button];
+        }
+        match self.policy {
+            DirectionPolicy::Allow => self.buttons[button],
+            DirectionPolicy::LastPressed => self.buttons[button] && last == button,
+            DirectionPolicy::Neutralize => false
+        }
+    }
+
+    // the 8 buttons as shifted out, A in bit 0
+    pub fn report(&self) -> u8 {
+        (0..8).fold(0, |report, button| report | (self.pressed(button) as u8) << button)
+    }
+
+    // state of one of the 8 buttons the console sees
+    fn pressed(&self, button: usize) -> bool {
+        let turbo = (self.frame / self.turbo_rate).is_multiple_of(2);
+        match button {
+            CONTROLLER_A => self.buttons[CONTROLLER_A] || (turbo && self.buttons[CONTROLLER_TURBO_A]),
+            CONTROLLER_B => self.buttons[CONTROLLER_B] || (turbo && self.buttons[CONTROLLER_TURBO_B]),
+            CONTROLLER_LEFT => self.direction(CONTROLLER_LEFT, CONTROLLER_RIGHT, self.last_horizontal),
+            CONTROLLER_RIGHT => self.direction(CONTROLLER_RIGHT, CONTROLLER_LEFT, self.last_horizontal),
+            CONTROLLER_UP => self.direction(CONTROLLER_UP, CONTROLLER_DOWN, self.last_vertical),
+            CONTROLLER_DOWN => self.direction(CONTROLLER_DOWN, CONTROLLER_UP, self.last_vertical),
+            _ => self.buttons[button]
         }
     }
+}
+
+impl Default for Controller {
+    fn default() -> Controller {
+        Controller::new()
+    }
+}
 
-    pub fn store_u8(&mut self, value: u8) {
+impl InputDevice for Controller {
+    fn store_u8(&mut self, value: u8) {
         self.strobe = value & 1 != 0;
         if self.strobe {
             self.index = CONTROLLER_A;
         }
     }
 
-    pub fn load_u8(&mut self) -> u8 {
+    fn load_u8(&mut self) -> u8 {
         if self.strobe {
-            return self.buttons[CONTROLLER_A] as u8;
+            return self.pressed(CONTROLLER_A) as u8;
         }
 
         if self.index < 8 {
-            let state = self.buttons[self.index];
+            let state = self.pressed(self.index);
             self.index += 1;
             return state as u8;
         }
         1
     }
+}
+
+// four player adapters
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MultitapKind {
+    // NES Four Score, plugged into both ports, data on bit 0
+    FourScore,
+    // Famicom Hori 4 Players Adapter, on the expansion port, data on bit 1
+    Hori
+}
+
+// each port shifts out 24 bits: pad 1 or 2, then pad 3 or 4, then a signature byte
+// telling the game which adapter is there. The signatures are in read order, bit 0 first
+pub struct Multitap {
+    kind: MultitapKind,
+    controllers: [Rc<RefCell<Controller>>; 4],
+    strobe: bool,
+    index: [usize; 2]
+}
+
+impl Multitap {
+    pub fn new(kind: MultitapKind, controllers: [Rc<RefCell<Controller>>; 4]) -> Multitap {
+        Multitap {
+            kind,
+            controllers,
+            strobe: false,
+            index: [0, 0]
+        }
+    }
+
+    fn signature(&self, port: usize) -> u32 {
+        match (self.kind, port) {
+            (MultitapKind::FourScore, 0) | (MultitapKind::Hori, 1) => 0x08,
+            _ => 0x04
+        }
+    }
+
+    fn store_u8(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        if self.strobe {
+            self.index = [0, 0];
+        }
+    }
+
+    fn load_u8(&mut self, port: usize) -> u8 {
+        let stream = self.controllers[port].borrow().report() as u32
+            | (self.controllers[port + 2].borrow().report() as u32) << 8
+            | self.signature(port) << 16;
+        let bit = if self.strobe {
+            (stream & 1) as u8
+        }
+        else if self.index[port] < 24 {
+            let bit = ((stream >> self.index[port]) & 1) as u8;
+            self.index[port] += 1;
+            bit
+        }
+        else {
+            1
+        };
+        match self.kind {
+            MultitapKind::FourScore => bit,
+            MultitapKind::Hori => bit << 1
+        }
+    }
+}
+
+// one side of a Multitap, what actually goes into a port
+pub struct MultitapPort {
+    tap: Rc<RefCell<Multitap>>,
+    port: usize
+}
+
+impl MultitapPort {
+    pub fn new(tap: &Rc<RefCell<Multitap>>, port: usize) -> MultitapPort {
+        MultitapPort {
+            tap: Rc::clone(tap),
+            port
+        }
+    }
+}
+
+impl InputDevice for MultitapPort {
+    // the strobe reaches the adapter through both ports, latching twice is harmless
+    fn store_u8(&mut self, value: u8) {
+        self.tap.borrow_mut().store_u8(value);
+    }
+
+    fn load_u8(&mut self) -> u8 {
+        self.tap.borrow_mut().load_u8(self.port)
+    }
+}
+
+// the photodiode keeps reporting light for a while after the beam went past
+const ZAPPER_LIGHT_SCANLINES: u32 = 24;
+// how many pixels around the aim point the sensor sees
+const ZAPPER_RADIUS: i32 = 2;
+const ZAPPER_BRIGHTNESS: u32 = 0xC0 * 3;
+
+// NES Zapper light gun: bit 3 is low while it sees light, bit 4 is the trigger
+pub struct Zapper {
+    // aim point in screen pixels, None when pointing away from the screen
+    position: Option<(u32, u32)>,
+    trigger: bool,
+    light: bool
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper {
+            position: None,
+            trigger: false,
+            light: false
+        }
+    }
 
-    pub fn update(&mut self, keycode: Keycode, pressed: bool) {
-        match keycode {
-            Keycode::Left => {
-                self.buttons[CONTROLLER_LEFT] = pressed;
-            },
-            Keycode::Right => {
-                self.buttons[CONTROLLER_RIGHT] = pressed;
-            },
-            Keycode::Up => {
-                self.buttons[CONTROLLER_UP] = pressed;
-            },
-            Keycode::Down => {
-                self.buttons[CONTROLLER_DOWN] = pressed;
-            },
-            Keycode::D => {
-                self.buttons[CONTROLLER_B] = pressed;
-            },
-            Keycode::F => {
-                self.buttons[CONTROLLER_A] = pressed;
-            },
-            Keycode::LCtrl => {
-                self.buttons[CONTROLLER_START] = pressed;
-            },
-            Keycode::LAlt => {
-                self.buttons[CONTROLLER_SELECT] = pressed;
-            },
-            _ => {}
-        }
-    }
-}
\ No newline at end of file
+    pub fn set_position(&mut self, position: Option<(u32, u32)>) {
+        self.position = position;
+    }
+
+    pub fn set_trigger(&mut self, pulled: bool) {
+        self.trigger = pulled;
+    }
+}
+
+impl Default for Zapper {
+    fn default() -> Zapper {
+        Zapper::new()
+    }
+}
+
+impl InputDevice for Zapper {
+    fn store_u8(&mut self, _value: u8) {}
+
+    fn load_u8(&mut self) -> u8 {
+        let mut value = 0;
+        if !self.light {
+            value |= 0x08;
+        }
+        if self.trigger {
+            value |= 0x10;
+        }
+        value
+    }
+
+    fn sense_light(&mut self, framebuffer: &[u8], scanline: u32, dot: u32) {
+        self.light = false;
+        let (x, y) = match self.position {
+            Some(position) => position,
+            None => return
+        };
+        for dy in -ZAPPER_RADIUS..=ZAPPER_RADIUS {
+            let row = y as i32 + dy;
+            // only pixels the beam drew recently in this frame are lit
+            if !(0..240).contains(&row) || row as u32 > scanline || scanline - row as u32 > ZAPPER_LIGHT_SCANLINES {
+                continue;
+            }
+            for dx in -ZAPPER_RADIUS..=ZAPPER_RADIUS {
+                let col = x as i32 + dx;
+                // the beam hasn't reached it yet on the current line
+                if !(0..256).contains(&col) || (row as u32 == scanline && col as u32 >= dot) {
+                    continue;
+                }
+                let offset = (row as usize * 256 + col as usize) * 4;
+                let brightness: u32 = framebuffer[offset..offset + 3].iter().map(|&c| c as u32).sum();
+                if brightness >= ZAPPER_BRIGHTNESS {
+                    self.light = true;
+                    return;
+                }
+            }
+        }
+    }
+}
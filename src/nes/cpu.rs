@@ -299,6 +299,11 @@ impl Cpu {
             return self.cycles - cycles;
         }
 
+        if self.bus.pending_irq() && !self.get_flag(CPU_INT_FLAG) {
+            self.handle_irq();
+            return self.cycles - cycles;
+        }
+
         let opcode = self.fetch_u8();
         self.page_cross = false;
 
@@ -699,6 +704,14 @@ impl Cpu {
         self.cycles += 7;
     }
 
+    fn handle_irq(&mut self) {
+        self.pushw(self.pc);
+        self.pushb((self.p & !CPU_B4_FLAG) | CPU_B5_FLAG);
+        self.set_flag(CPU_INT_FLAG, true);
+        self.pc = self.bus.load_u16(CPU_BRK_VECTOR);
+        self.cycles += 7;
+    }
+
     pub fn run(&mut self, max_cycles: u64) {
         let mut remaining = max_cycles as i64;
         while remaining > 0 {
@@ -0,0 +1,677 @@
+use super::mapper::{self, Mapper};
+use std::error::Error;
+
+// Famicom Disk System: the RAM adapter maps 32K of PRG-RAM at $6000-$DFFF, the 8K BIOS
+// at $E000-$FFFF and 8K of CHR-RAM, and talks to the disk drive through $4020-$4033
+
+const FDS_HEADER_SIZE: usize = 16;
+const FDS_SIDE_SIZE: usize = 65500;
+const FDS_BIOS_SIZE: usize = 0x2000;
+
+// gaps are written as zero bits, before the first block and between blocks
+const FDS_LEAD_IN: usize = 28300 / 8;
+const FDS_BLOCK_GAP: usize = 976 / 8;
+
+// CPU cycles between two bytes under the head, and from motor start to the first one
+const FDS_BYTE_CYCLES: u32 = 150;
+const FDS_SPIN_UP_CYCLES: u32 = 50000;
+// the BIOS has to see the drive empty for a while to notice a disk change
+const FDS_INSERT_DELAY: u32 = 1_789_773;
+
+// master volume multipliers for the wave channel, 2/2 2/3 2/4 2/5 scaled so that
+// full gain gives back the 6 bit sample
+const FDS_WAVE_VOLUME: [u32; 4] = [36, 24, 17, 14];
+// modulation table entries, 4 resets the counter
+const FDS_MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+pub fn is_fds_image(data: &[u8]) -> bool {
+    data.starts_with(b"FDS\x1A") || data.starts_with(b"\x01*NINTENDO-HVC*")
+}
+
+fn update_crc(crc: u16, value: u8) -> u16 {
+    let mut crc = crc;
+    for n in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if value & (1 << n) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// turns one side of an .fds image, which only has the block contents, into what the
+// drive sees: lead-in gap, then each block as start mark, data, CRC and gap
+fn raw_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0u8; FDS_LEAD_IN];
+    let mut pos = 0;
+    let mut file_size = 0;
+    while pos < side.len() {
+        let len = match side[pos] {
+            1 => 56,
+            2 => 2,
+            3 => {
+                if pos + 15 <= side.len() {
+                    file_size = side[pos + 13] as usize | (side[pos + 14] as usize) << 8;
+                }
+                16
+            },
+            4 => 1 + file_size,
+            // anything else is the unused part of the disk
+            _ => break
+        };
+        let block = &side[pos..std::cmp::min(pos + len, side.len())];
+        let mut crc = update_crc(0, 0x80);
+        raw.push(0x80);
+        for &b in block {
+            crc = update_crc(crc, b);
+            raw.push(b);
+        }
+        crc = update_crc(update_crc(crc, 0), 0);
+        raw.push(crc as u8);
+        raw.push((crc >> 8) as u8);
+        raw.extend_from_slice(&[0u8; FDS_BLOCK_GAP]);
+        pos += len;
+    }
+    // leave room for files the game writes later
+    let min_len = FDS_LEAD_IN + FDS_SIDE_SIZE;
+    if raw.len() < min_len {
+        raw.resize(min_len, 0);
+    }
+    raw
+}
+
+// volume and modulator units share the envelope logic
+struct FdsEnvelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    timer: u32,
+    frequency: u16
+}
+
+impl FdsEnvelope {
+    fn new() -> FdsEnvelope {
+        FdsEnvelope {
+            speed: 0,
+            gain: 0,
+            increase: false,
+            disabled: true,
+            timer: 0,
+            frequency: 0
+        }
+    }
+
+    fn write_control(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.disabled = value & 0x80 != 0;
+        self.reset_timer(master_speed);
+        if self.disabled {
+            self.gain = self.speed;
+        }
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    // returns whether the gain was stepped
+    fn tick(&mut self, master_speed: u8) -> bool {
+        if self.disabled || master_speed == 0 {
+            return false;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.reset_timer(master_speed);
+            if self.increase && self.gain < 32 {
+                self.gain += 1;
+            }
+            else if !self.increase && self.gain > 0 {
+                self.gain -= 1;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+struct FdsAudio {
+    wave_table: [u8; 64],
+    wave_write: bool,
+    wave_halt: bool,
+    wave_position: usize,
+    wave_accumulator: u16,
+    envelopes_disabled: bool,
+    master_volume: usize,
+    master_speed: u8,
+    volume: FdsEnvelope,
+    modulator: FdsEnvelope,
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_counter: i8,
+    mod_halt: bool,
+    mod_accumulator: u16,
+    mod_output: i32,
+    output: u8
+}
+
+impl FdsAudio {
+    fn new() -> FdsAudio {
+        FdsAudio {
+            wave_table: [0; 64],
+            wave_write: false,
+            wave_halt: true,
+            wave_position: 0,
+            wave_accumulator: 0,
+            envelopes_disabled: false,
+            master_volume: 0,
+            master_speed: 0xE8,
+            volume: FdsEnvelope::new(),
+            modulator: FdsEnvelope::new(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_halt: true,
+            mod_accumulator: 0,
+            mod_output: 0,
+            output: 0
+        }
+    }
+
+    fn set_mod_counter(&mut self, value: i32) {
+        // 7 bit signed
+        self.mod_counter = (((value & 0x7F) << 1) as i8) >> 1;
+    }
+
+    // pitch offset from the modulator, as worked out by loopy from the hardware
+    fn update_mod_output(&mut self) {
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.modulator.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        }
+        else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.volume.frequency as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4040..=0x407F if self.wave_write => self.wave_table[(address & 0x3F) as usize] = value & 0x3F,
+            0x4080 => self.volume.write_control(value, self.master_speed),
+            0x4082 => {
+                self.volume.frequency = (self.volume.frequency & 0x0F00) | value as u16;
+                self.update_mod_output();
+            },
+            0x4083 => {
+                self.volume.frequency = (self.volume.frequency & 0xFF) | ((value as u16 & 0x0F) << 8);
+                self.wave_halt = value & 0x80 != 0;
+                self.envelopes_disabled = value & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_disabled {
+                    self.volume.reset_timer(self.master_speed);
+                    self.modulator.reset_timer(self.master_speed);
+                }
+                self.update_mod_output();
+            },
+            0x4084 => {
+                self.modulator.write_control(value, self.master_speed);
+                self.update_mod_output();
+            },
+            0x4085 => {
+                self.set_mod_counter(value as i32);
+                self.update_mod_output();
+            },
+            0x4086 => self.modulator.frequency = (self.modulator.frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.modulator.frequency = (self.modulator.frequency & 0xFF) | ((value as u16 & 0x0F) << 8);
+                self.mod_halt = value & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            },
+            // the table can only be written while the modulator is halted, two entries at a time
+            0x4088 if self.mod_halt => {
+                self.mod_table[self.mod_position] = value & 7;
+                self.mod_table[(self.mod_position + 1) & 0x3F] = value & 7;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            },
+            0x4089 => {
+                self.master_volume = (value & 3) as usize;
+                self.wave_write = value & 0x80 != 0;
+            },
+            0x408A => self.master_speed = value,
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.wave_halt && !self.envelopes_disabled {
+            self.volume.tick(self.master_speed);
+            if self.modulator.tick(self.master_speed) {
+                self.update_mod_output();
+            }
+        }
+
+        let mod_frequency = self.modulator.frequency;
+        if !self.mod_halt && mod_frequency > 0 {
+            let (accumulator, overflow) = self.mod_accumulator.overflowing_add(mod_frequency);
+            self.mod_accumulator = accumulator;
+            if overflow {
+                let step = self.mod_table[self.mod_position];
+                let counter = if step == 4 { 0 } else { self.mod_counter as i32 + FDS_MOD_STEPS[step as usize] as i32 };
+                self.set_mod_counter(counter);
+                self.mod_position = (self.mod_position + 1) & 0x3F;
+                self.update_mod_output();
+            }
+        }
+
+        // the wave doesn't advance while its RAM is writable
+        let frequency = self.volume.frequency as i32 + self.mod_output;
+        if !self.wave_halt && !self.wave_write && frequency > 0 {
+            let (accumulator, overflow) = self.wave_accumulator.overflowing_add(frequency as u16);
+            self.wave_accumulator = accumulator;
+            if overflow {
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+
+        let level = std::cmp::min(self.volume.gain as u32, 32) * FDS_WAVE_VOLUME[self.master_volume];
+        let sample = self.wave_table[self.wave_position] as u32 * level / 1152;
+        self.output = (sample >> 2) as u8;
+    }
+}
+
+pub struct Fds {
+    bios: Vec<u8>,
+    ram: Vec<u8>,
+    chr_ram: [u8; 0x2000],
+    // raw sides back to back, see raw_side
+    disk: Vec<u8>,
+    sides: Vec<(usize, usize)>,
+    side: Option<usize>,
+    next_side: Option<usize>,
+    insert_delay: u32,
+
+    disk_enabled: bool,
+    sound_enabled: bool,
+    vertical_mirroring: bool,
+    ext_out: u8,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    transfer_complete: bool,
+    position: usize,
+    delay: u32,
+    read_data: u8,
+    write_data: u8,
+    crc: u16,
+
+    audio: FdsAudio
+}
+
+impl Fds {
+    pub fn new(image: &[u8], bios: &[u8]) -> Result<Fds, Box<dyn Error>> {
+        if bios.len() != FDS_BIOS_SIZE {
+            return Err(format!("FDS BIOS must be {} bytes, got {}", FDS_BIOS_SIZE, bios.len()).into());
+        }
+        // the fwNES header only adds the side count, which the size gives anyway
+        let data = if image.starts_with(b"FDS\x1A") { &image[FDS_HEADER_SIZE.min(image.len())..] } else { image };
+        if data.len() < FDS_SIDE_SIZE {
+            return Err("FDS image has no complete disk side".into());
+        }
+
+        let mut disk = Vec::new();
+        let mut sides = Vec::new();
+        for side in data.chunks_exact(FDS_SIDE_SIZE) {
+            let raw = raw_side(side);
+            sides.push((disk.len(), raw.len()));
+            disk.extend_from_slice(&raw);
+        }
+
+        Ok(Fds {
+            bios: bios.to_vec(),
+            ram: vec![0u8; 0x8000],
+            chr_ram: [0; 0x2000],
+            disk,
+            sides,
+            side: Some(0),
+            next_side: None,
+            insert_delay: 0,
+            disk_enabled: false,
+            sound_enabled: false,
+            vertical_mirroring: true,
+            ext_out: 0,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_repeat: false,
+            irq_enabled: false,
+            timer_irq: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            previous_crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            transfer_complete: false,
+            position: 0,
+            delay: 0,
+            read_data: 0,
+            write_data: 0,
+            crc: 0,
+            audio: FdsAudio::new()
+        })
+    }
+
+    fn clock_irq(&mut self) {
+        if self.irq_enabled {
+            if self.irq_counter == 0 {
+                self.timer_irq = true;
+                self.irq_counter = self.irq_reload;
+                if !self.irq_repeat {
+                    self.irq_enabled = false;
+                }
+            }
+            else {
+                self.irq_counter -= 1;
+            }
+        }
+    }
+
+    fn clock_disk(&mut self) {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+            if self.insert_delay == 0 {
+                self.side = self.next_side.take();
+            }
+        }
+
+        let (start, len) = match self.side {
+            Some(side) if self.motor_on => self.sides[side],
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = FDS_SPIN_UP_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut need_irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.disk[start + self.position];
+            if !self.previous_crc_control {
+                self.crc = update_crc(self.crc, data);
+            }
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc = 0;
+            }
+            else if data != 0 && !self.gap_ended {
+                // the start mark itself isn't handed to the CPU
+                self.gap_ended = true;
+                need_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+        }
+        else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+            if !self.disk_ready {
+                data = 0;
+            }
+            if !self.crc_control {
+                self.crc = update_crc(self.crc, data);
+            }
+            else {
+                if !self.previous_crc_control {
+                    self.crc = update_crc(update_crc(self.crc, 0), 0);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            self.disk[start + self.position] = data;
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= len {
+            self.motor_on = false;
+        }
+        else {
+            self.delay = FDS_BYTE_CYCLES;
+        }
+    }
+
+    fn load_register(&mut self, address: u16) -> u8 {
+        match address {
+            0x4030 => {
+                let mut value = 0;
+                if self.timer_irq {
+                    value |= 0x01;
+                }
+                if self.transfer_complete {
+                    value |= 0x02;
+                }
+                if self.crc != 0 {
+                    value |= 0x10;
+                }
+                if self.end_of_head {
+                    value |= 0x40;
+                }
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                value
+            },
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                self.read_data
+            },
+            0x4032 => {
+                let inserted = self.side.is_some();
+                let mut value = 0x40;
+                if !inserted {
+                    value |= 0x05;
+                }
+                if !inserted || !self.scanning {
+                    value |= 0x02;
+                }
+                value
+            },
+            // bit 7 is the battery status, good
+            0x4033 => 0x80 | (self.ext_out & 0x7F),
+            0x4040..=0x407F => 0x40 | self.audio.wave_table[(address & 0x3F) as usize],
+            0x4090 => 0x40 | self.audio.volume.gain,
+            0x4092 => 0x40 | self.audio.modulator.gain,
+            _ => 0
+        }
+    }
+
+    fn store_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xFF00) | value as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00FF) | ((value as u16) << 8),
+            0x4022 => {
+                self.irq_repeat = value & 1 != 0;
+                self.irq_enabled = value & 2 != 0 && self.disk_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                }
+                else {
+                    self.timer_irq = false;
+                }
+            },
+            0x4023 => {
+                self.disk_enabled = value & 1 != 0;
+                self.sound_enabled = value & 2 != 0;
+                if !self.disk_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            },
+            0x4024 if self.disk_enabled => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            },
+            0x4025 if self.disk_enabled => {
+                self.motor_on = value & 0x01 != 0;
+                self.reset_transfer = value & 0x02 != 0;
+                self.read_mode = value & 0x04 != 0;
+                self.vertical_mirroring = value & 0x08 == 0;
+                self.crc_control = value & 0x10 != 0;
+                self.disk_ready = value & 0x40 != 0;
+                self.disk_irq_enabled = value & 0x80 != 0;
+                self.disk_irq = false;
+            },
+            0x4026 if self.disk_enabled => self.ext_out = value,
+            0x4040..=0x408A if self.sound_enabled => self.audio.write(address, value),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address >= 0xE000 {
+            self.bios[(address & 0x1FFF) as usize]
+        }
+        else if address >= 0x6000 {
+            self.ram[(address - 0x6000) as usize]
+        }
+        else {
+            self.load_register(address)
+        }
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.chr_ram[(address & 0x1FFF) as usize]
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if address >= 0xE000 {
+            // BIOS ROM
+        }
+        else if address >= 0x6000 {
+            self.ram[(address - 0x6000) as usize] = value;
+        }
+        else {
+            self.store_register(address, value);
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        self.chr_ram[(address & 0x1FFF) as usize] = value;
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        if self.vertical_mirroring { mapper::MIRRORING_VERTICAL } else { mapper::MIRRORING_HORIZONTAL }
+    }
+
+    fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.clock_irq();
+            self.clock_disk();
+            self.audio.clock();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn audio_out(&self) -> u8 {
+        self.audio.output
+    }
+
+    // the disk contents, gaps included, so that writes survive a restart
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.disk)
+    }
+
+    fn disk_sides(&self) -> usize {
+        self.sides.len()
+    }
+
+    fn inserted_disk(&self) -> Option<usize> {
+        self.side.or(self.next_side)
+    }
+
+    fn insert_disk(&mut self, side: Option<usize>) {
+        // eject first and insert later, so the BIOS sees the change
+        self.side = None;
+        self.next_side = side.filter(|&s| s < self.sides.len());
+        self.insert_delay = if self.next_side.is_some() { FDS_INSERT_DELAY } else { 0 };
+    }
+}
@@ -0,0 +1,240 @@
+use super::rom::{Mirroring, Timing};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// the database shipped with the emulator, see gamedb.txt for the format
+const EMBEDDED_DB: &str = include_str!("gamedb.txt");
+
+// known-good header values for a dump, keyed by the CRC32 of PRG+CHR
+pub struct GameInfo {
+    pub crc32: u32,
+    // when present, must match too, to tell apart CRC32 collisions
+    pub sha1: Option<[u8; 20]>,
+    pub mapper_id: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing
+}
+
+pub struct GameDb {
+    games: HashMap<u32, Vec<GameInfo>>
+}
+
+fn parse_sha1(s: &str) -> Result<Option<[u8; 20]>, Box<dyn Error>> {
+    if s == "-" {
+        return Ok(None);
+    }
+    if s.len() != 40 {
+        return Err("SHA-1 must be 40 hex digits".into());
+    }
+    let mut sha1 = [0u8; 20];
+    for (i, b) in sha1.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(Some(sha1))
+}
+
+fn parse_game(line: &str) -> Result<GameInfo, Box<dyn Error>> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 10 {
+        return Err(format!("expected 10 fields, found {}", fields.len()).into());
+    }
+    let mirroring = match fields[4] {
+        "H" => Mirroring::Horizontal,
+        "V" => Mirroring::Vertical,
+        "4" => Mirroring::FourScreen,
+        m => return Err(format!("invalid mirroring {}", m).into())
+    };
+    let timing = match fields[9] {
+        "ntsc" => Timing::Ntsc,
+        "pal" => Timing::Pal,
+        "multi" => Timing::MultiRegion,
+        "dendy" => Timing::Dendy,
+        t => return Err(format!("invalid timing {}", t).into())
+    };
+    Ok(GameInfo {
+        crc32: u32::from_str_radix(fields[0], 16)?,
+        sha1: parse_sha1(fields[1])?,
+        mapper_id: fields[2].parse()?,
+        submapper: fields[3].parse()?,
+        mirroring,
+        prg_ram_size: fields[5].parse()?,
+        prg_nvram_size: fields[6].parse()?,
+        chr_ram_size: fields[7].parse()?,
+        chr_nvram_size: fields[8].parse()?,
+        timing
+    })
+}
+
+// value of attribute name in the first <tag ...> element of an XML fragment
+fn xml_attribute<'a>(xml: &'a str, tag: &str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{} ", tag))?;
+    let element = &xml[start..start + xml[start..].find('>')?];
+    let pattern = format!(" {}=\"", name);
+    let value_start = element.find(&pattern)? + pattern.len();
+    let value_len = element[value_start..].find('"')?;
+    Some(&element[value_start..value_start + value_len])
+}
+
+fn xml_size(game: &str, tag: &str) -> Result<usize, Box<dyn Error>> {
+    Ok(xml_attribute(game, tag, "size").map_or(Ok(0), |s| s.parse())?)
+}
+
+// one <game> element of the NES 2.0 XML database
+fn parse_xml_game(game: &str) -> Result<GameInfo, Box<dyn Error>> {
+    let crc32 = xml_attribute(game, "rom", "crc32").ok_or("missing <rom crc32>")?;
+    let mirroring = match xml_attribute(game, "pcb", "mirroring") {
+        Some("V") => Mirroring::Vertical,
+        Some("4") => Mirroring::FourScreen,
+        // mapper controlled boards keep the horizontal default, the mapper sets it anyway
+        _ => Mirroring::Horizontal
+    };
+    let timing = match xml_attribute(game, "console", "region") {
+        Some("1") => Timing::Pal,
+        Some("2") => Timing::MultiRegion,
+        Some("3") => Timing::Dendy,
+        _ => Timing::Ntsc
+    };
+    Ok(GameInfo {
+        crc32: u32::from_str_radix(crc32, 16)?,
+        sha1: xml_attribute(game, "rom", "sha1").map_or(Ok(None), parse_sha1)?,
+        mapper_id: xml_attribute(game, "pcb", "mapper").ok_or("missing <pcb mapper>")?.parse()?,
+        submapper: xml_attribute(game, "pcb", "submapper").map_or(Ok(0), |s| s.parse())?,
+        mirroring,
+        prg_ram_size: xml_size(game, "prgram")?,
+        prg_nvram_size: xml_size(game, "prgnvram")?,
+        chr_ram_size: xml_size(game, "chrram")?,
+        chr_nvram_size: xml_size(game, "chrnvram")?,
+        timing
+    })
+}
+
+impl GameDb {
+    // either the line format of gamedb.txt or nes20db.xml as published
+    pub fn parse(text: &str) -> Result<GameDb, Box<dyn Error>> {
+        if text.trim_start().starts_with('<') {
+            return GameDb::parse_xml(text);
+        }
+        let mut games: HashMap<u32, Vec<GameInfo>> = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let game = parse_game(line).map_err(|e| format!("game database line {}: {}", n + 1, e))?;
+            games.entry(game.crc32).or_default().push(game);
+        }
+        Ok(GameDb { games })
+    }
+
+    fn parse_xml(text: &str) -> Result<GameDb, Box<dyn Error>> {
+        let mut games: HashMap<u32, Vec<GameInfo>> = HashMap::new();
+        for (n, game) in text.split("<game>").skip(1).enumerate() {
+            let game = game.split("</game>").next().unwrap_or("");
+            let game = parse_xml_game(game).map_err(|e| format!("game database entry {}: {}", n + 1, e))?;
+            games.entry(game.crc32).or_default().push(game);
+        }
+        Ok(GameDb { games })
+    }
+
+    pub fn from_file(filename: &Path) -> Result<GameDb, Box<dyn Error>> {
+        GameDb::parse(&fs::read_to_string(filename)?)
+            .map_err(|e| format!("{}: {}", filename.display(), e).into())
+    }
+
+    pub fn embedded() -> &'static GameDb {
+        static DB: OnceLock<GameDb> = OnceLock::new();
+        DB.get_or_init(|| GameDb::parse(EMBEDDED_DB).expect("invalid embedded game database"))
+    }
+
+    pub fn lookup(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&GameInfo> {
+        self.games.get(&crc32)?.iter().find(|g| g.sha1.is_none_or(|s| &s == sha1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.values().map(|g| g.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::checksum;
+    use super::super::rom::NesRom;
+
+    // iNES 1.0 image claiming NROM with horizontal mirroring, 16K PRG + 8K CHR
+    fn bad_header_rom() -> (Vec<u8>, u32) {
+        let mut data = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend((0..0x6000).map(|i| (i * 7 + (i >> 8)) as u8));
+        let crc32 = checksum::crc32(&data[16..]);
+        (data, crc32)
+    }
+
+    #[test]
+    fn text_entry_corrects_header() {
+        let (data, crc32) = bad_header_rom();
+        let db = GameDb::parse(&format!("{:08X} - 3 0 V 0 0 0 0 ntsc\n", crc32)).unwrap();
+        assert_eq!(db.len(), 1);
+
+        let rom = NesRom::from_bytes(&data).unwrap();
+        assert_eq!(rom.mapper_id, 0);
+        assert!(!rom.in_database);
+
+        let rom = NesRom::from_bytes_with_db(&data, Some(&db)).unwrap();
+        assert!(rom.in_database);
+        assert_eq!(rom.mapper_id, 3);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert_eq!(rom.prg_ram_size, 0);
+    }
+
+    #[test]
+    fn xml_entry_corrects_header() {
+        let (data, crc32) = bad_header_rom();
+        let mut sha1 = checksum::Sha1::new();
+        sha1.update(&data[16..]);
+        let sha1 = checksum::to_hex(&sha1.finish());
+        let xml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db date="2024-01-01">
+<game>
+<!-- Test\Bad Header.nes -->
+<prgrom size="16384" crc32="00000000" sha1="0000000000000000000000000000000000000000" sum16="0000"/>
+<chrrom size="8192" crc32="00000000" sha1="0000000000000000000000000000000000000000" sum16="0000"/>
+<rom size="24576" crc32="{:08X}" sha1="{}"/>
+<prgnvram size="8192"/>
+<pcb mapper="1" submapper="0" mirroring="V" battery="1"/>
+<console type="0" region="1"/>
+</game>
+</nes20db>
+"#, crc32, sha1);
+        let db = GameDb::parse(&xml).unwrap();
+        assert_eq!(db.len(), 1);
+
+        let rom = NesRom::from_bytes_with_db(&data, Some(&db)).unwrap();
+        assert!(rom.in_database);
+        assert_eq!(rom.mapper_id, 1);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert_eq!(rom.prg_nvram_size, 0x2000);
+        assert!(rom.has_battery());
+        assert_eq!(rom.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn sha1_mismatch_is_ignored() {
+        let (data, crc32) = bad_header_rom();
+        let db = GameDb::parse(&format!("{:08X} {} 3 0 V 0 0 0 0 ntsc\n", crc32, "00".repeat(20))).unwrap();
+        let rom = NesRom::from_bytes_with_db(&data, Some(&db)).unwrap();
+        assert!(!rom.in_database);
+        assert_eq!(rom.mapper_id, 0);
+    }
+}
@@ -1,22 +1,229 @@
 use super::rom;
+use super::apu;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::error::Error;
+use std::io::Read;
+
+// nametable offsets for the usual mirroring layouts
+pub const MIRRORING_HORIZONTAL: [u16; 4] = [0, 0, 0x400, 0x400];
+pub const MIRRORING_VERTICAL: [u16; 4] = [0, 0x400, 0, 0x400];
+pub const MIRRORING_SINGLE_LOW: [u16; 4] = [0, 0, 0, 0];
+pub const MIRRORING_SINGLE_HIGH: [u16; 4] = [0x400, 0x400, 0x400, 0x400];
+pub const MIRRORING_FOUR_SCREEN: [u16; 4] = [0, 0x400, 0x800, 0xC00];
 
 pub trait Mapper {
-    fn load_prg_u8(&self, address: u16) -> u8;
+    fn load_prg_u8(&mut self, address: u16) -> u8;
     fn load_chr_u8(&self, address: u16) -> u8;
     fn store_prg_u8(&mut self, address: u16, value: u8);
     fn store_chr_u8(&mut self, address: u16, value: u8);
     fn mirroring(&self) -> [u16; 4];
+
+    // nametable accesses, vram is the 2K of PPU internal RAM plus 2K for four-screen boards
+    fn load_nt_u8(&self, address: u16, vram: &[u8]) -> u8 {
+        vram[mirror_address(self.mirroring(), address)]
+    }
+
+    fn store_nt_u8(&mut self, address: u16, value: u8, vram: &mut [u8]) {
+        vram[mirror_address(self.mirroring(), address)] = value;
+    }
+
+    // called by the PPU with every address it puts on the VRAM bus and its cycle count,
+    // before the access itself
+    fn ppu_address(&mut self, _address: u16, _cycle: u64) {}
+
+    // called by the bus on CPU writes to $2000-$3FFF, register is the address modulo 8
+    fn ppu_register_write(&mut self, _register: u16, _value: u8) {}
+
+    // called after the CPU ran for the given number of cycles
+    fn run(&mut self, _cycles: u64) {}
+
+    // level of the cartridge /IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // expansion audio output, mixed with the APU channels
+    fn audio_out(&self) -> u8 {
+        0
+    }
+
+    // PRG-RAM to persist across sessions, for boards with a battery
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Famicom Disk System sides, 0 for cartridges
+    fn disk_sides(&self) -> usize {
+        0
+    }
+
+    fn inserted_disk(&self) -> Option<usize> {
+        None
+    }
+
+    // swaps in the given disk side, None ejects the disk
+    fn insert_disk(&mut self, _side: Option<usize>) {}
 }
 
-pub fn from_file(filename: &str) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
-    let rom = rom::NesRom::new(filename)?;
-    match rom.mapper_id {
-        0 => Ok(Rc::new(RefCell::new(Mapper0 { ram: [0; 0x2000], rom: Box::new(rom) }))),
-        _ => Err("Invalid mapper id".into())
+// maps a $2000-$3EFF address to an offset into the PPU nametable RAM
+pub fn mirror_address(mirroring: [u16; 4], address: u16) -> usize {
+    let table = ((address >> 10) & 3) as usize;
+    ((mirroring[table] + (address & 0x3FF)) & 0xFFF) as usize
+}
+
+pub type MapperConstructor = Box<dyn Fn(rom::NesRom) -> Rc<RefCell<dyn Mapper>>>;
+
+pub struct MapperInfo {
+    pub id: u16,
+    // None matches every submapper
+    pub submapper: Option<u8>,
+    pub name: String,
+    pub board: String
+}
+
+struct MapperEntry {
+    info: MapperInfo,
+    constructor: Option<MapperConstructor>
+}
+
+// mappers we know about but don't emulate, so errors can say what the cartridge is
+static KNOWN_MAPPERS: [(u16, &str, &str); 24] = [
+    (1, "MMC1", "SxROM"),
+    (13, "CPROM", "NES-CPROM"),
+    (15, "100-in-1 Contra Function 16", "K-1029/K-1030P"),
+    (16, "Bandai FCG", "FCG-1/FCG-2/LZ93D50"),
+    (18, "Jaleco SS88006", "JF-xx"),
+    (28, "Action 53", "INL-A53"),
+    (30, "UNROM 512", "UNROM-512"),
+    (32, "Irem G-101", "G-101"),
+    (33, "Taito TC0190", "TC0190/TC0350"),
+    (48, "Taito TC0690", "TC0690"),
+    (64, "Tengen RAMBO-1", "800032"),
+    (65, "Irem H3001", "H3001"),
+    (67, "Sunsoft-3", "Sunsoft-3"),
+    (68, "Sunsoft-4", "NTBROM"),
+    (70, "Bandai 74161/7432", "Bandai discrete"),
+    (73, "Konami VRC3", "VRC3"),
+    (75, "Konami VRC1", "VRC1"),
+    (80, "Taito X1-005", "X1-005"),
+    (85, "Konami VRC7", "VRC7"),
+    (87, "Jaleco/Konami CNROM variant", "JF-xx"),
+    (118, "MMC3 with nametable control", "TxSROM"),
+    (119, "MMC3 with CHR-ROM and CHR-RAM", "TQROM"),
+    (206, "Namco 108", "DxROM"),
+    (210, "Namco 175/340", "Namco 175/340")
+];
+
+// Maps mapper/submapper numbers to constructors. Frontends and other crates can
+// register their own Mapper implementations, or override the built-in ones
+pub struct MapperRegistry {
+    entries: Vec<MapperEntry>
+}
+
+impl MapperRegistry {
+    // an empty registry that still knows the names of unsupported mappers
+    pub fn empty() -> MapperRegistry {
+        let entries = KNOWN_MAPPERS.iter().map(|&(id, name, board)| MapperEntry {
+            info: MapperInfo { id, submapper: None, name: name.to_string(), board: board.to_string() },
+            constructor: None
+        }).collect();
+        MapperRegistry { entries }
+    }
+
+    // all the mappers implemented by this crate
+    pub fn new() -> MapperRegistry {
+        let mut registry = MapperRegistry::empty();
+        registry.register(0, None, "NROM", "NROM", |rom| Rc::new(RefCell::new(Mapper0::new(rom))));
+        registry.register(2, None, "UxROM", "UNROM/UOROM", |rom| Rc::new(RefCell::new(Mapper2::new(rom))));
+        registry.register(3, None, "CNROM", "CNROM", |rom| Rc::new(RefCell::new(Mapper3::new(rom))));
+        registry.register(4, None, "MMC3", "TxROM", |rom| Rc::new(RefCell::new(Mapper4::new(rom))));
+        registry.register(5, None, "MMC5", "ExROM", |rom| Rc::new(RefCell::new(Mapper5::new(rom))));
+        registry.register(7, None, "AxROM", "ANROM/AOROM", |rom| Rc::new(RefCell::new(Mapper7::new(rom))));
+        registry.register(9, None, "MMC2", "PxROM", |rom| Rc::new(RefCell::new(Mapper9::new(rom, false))));
+        registry.register(10, None, "MMC4", "FxROM", |rom| Rc::new(RefCell::new(Mapper9::new(rom, true))));
+        registry.register(11, None, "Color Dreams", "Color Dreams", |rom| Rc::new(RefCell::new(Mapper66::new(rom))));
+        registry.register(19, None, "Namco 163", "Namco 129/163", |rom| Rc::new(RefCell::new(Mapper19::new(rom))));
+        registry.register(21, None, "Konami VRC4", "VRC4a/VRC4c", |rom| Rc::new(RefCell::new(Mapper21::new(rom))));
+        registry.register(22, None, "Konami VRC2", "VRC2a", |rom| Rc::new(RefCell::new(Mapper21::new(rom))));
+        registry.register(23, None, "Konami VRC2/VRC4", "VRC2b/VRC4e/VRC4f", |rom| Rc::new(RefCell::new(Mapper21::new(rom))));
+        registry.register(24, None, "Konami VRC6", "VRC6a", |rom| Rc::new(RefCell::new(Mapper24::new(rom))));
+        registry.register(25, None, "Konami VRC2/VRC4", "VRC2c/VRC4b/VRC4d", |rom| Rc::new(RefCell::new(Mapper21::new(rom))));
+        registry.register(26, None, "Konami VRC6", "VRC6b", |rom| Rc::new(RefCell::new(Mapper24::new(rom))));
+        registry.register(34, None, "BNROM/NINA-001", "BNROM/NINA-001", |rom| Rc::new(RefCell::new(Mapper34::new(rom))));
+        registry.register(66, None, "GxROM", "GNROM/MHROM", |rom| Rc::new(RefCell::new(Mapper66::new(rom))));
+        registry.register(69, None, "Sunsoft FME-7", "JLROM/JSROM", |rom| Rc::new(RefCell::new(Mapper69::new(rom))));
+        registry.register(71, None, "Camerica", "BF909x", |rom| Rc::new(RefCell::new(Mapper71::new(rom))));
+        registry.register(79, None, "NINA-03/06", "NINA-03/NINA-06", |rom| Rc::new(RefCell::new(Mapper66::new(rom))));
+        registry
     }
+
+    // registers a mapper, replacing any previous entry for the same mapper/submapper
+    pub fn register<F>(&mut self, id: u16, submapper: Option<u8>, name: &str, board: &str, constructor: F)
+        where F: Fn(rom::NesRom) -> Rc<RefCell<dyn Mapper>> + 'static {
+        self.entries.retain(|e| e.info.id != id || e.info.submapper != submapper);
+        self.entries.push(MapperEntry {
+            info: MapperInfo { id, submapper, name: name.to_string(), board: board.to_string() },
+            constructor: Some(Box::new(constructor))
+        });
+    }
+
+    // an exact submapper match wins over an entry covering all of them
+    fn entry(&self, id: u16, submapper: u8) -> Option<&MapperEntry> {
+        self.entries.iter()
+            .find(|e| e.info.id == id && e.info.submapper == Some(submapper))
+            .or_else(|| self.entries.iter().find(|e| e.info.id == id && e.info.submapper.is_none()))
+    }
+
+    pub fn info(&self, id: u16, submapper: u8) -> Option<&MapperInfo> {
+        self.entry(id, submapper).map(|e| &e.info)
+    }
+
+    pub fn is_supported(&self, id: u16, submapper: u8) -> bool {
+        self.entry(id, submapper).is_some_and(|e| e.constructor.is_some())
+    }
+
+    pub fn create(&self, rom: rom::NesRom) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
+        let id = rom.mapper_id;
+        let submapper = rom.submapper;
+        match self.entry(id, submapper) {
+            Some(MapperEntry { constructor: Some(constructor), .. }) => Ok(constructor(rom)),
+            Some(MapperEntry { info, .. }) => {
+                Err(format!("unsupported mapper {}.{} ({}, {} board)", id, submapper, info.name, info.board).into())
+            },
+            None => Err(format!("unsupported mapper {}.{}", id, submapper).into())
+        }
+    }
+
+    pub fn from_file(&self, filename: &str) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
+        self.create(rom::NesRom::new(filename)?)
+    }
+
+    pub fn from_bytes(&self, data: &[u8]) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
+        self.create(rom::NesRom::from_bytes(data)?)
+    }
+
+    pub fn from_reader<R: Read>(&self, reader: R) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
+        self.create(rom::NesRom::from_reader(reader)?)
+    }
+}
+
+impl Default for MapperRegistry {
+    fn default() -> MapperRegistry {
+        MapperRegistry::new()
+    }
+}
+
+pub fn from_file(filename: &str) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
+    MapperRegistry::new().from_file(filename)
+}
+
+pub fn from_bytes(data: &[u8]) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
+    MapperRegistry::new().from_bytes(data)
+}
+
+pub fn from_reader<R: Read>(reader: R) -> Result<Rc<RefCell<dyn Mapper>>, Box<dyn Error>> {
+    MapperRegistry::new().from_reader(reader)
 }
 
 struct Mapper0 {
@@ -24,20 +231,29 @@ struct Mapper0 {
     pub rom: Box<rom::NesRom>
 }
 
+impl Mapper0 {
+    fn new(rom: rom::NesRom) -> Mapper0 {
+        let mut ram = [0; 0x2000];
+        rom.load_trainer(&mut ram);
+        Mapper0 {
+            ram,
+            rom: Box::new(rom)
+        }
+    }
+}
+
 impl Mapper for Mapper0 {
-    fn load_prg_u8(&self, address: u16) -> u8 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
         if address < 0x8000 {
-            return unsafe { *self.ram.get_unchecked((address & 0x1FFF) as usize) };
-        }
-        else if self.rom.prg_rom.len() > 16384 {
-            return unsafe { *self.rom.prg_rom.get_unchecked((address & 0x7FFF) as usize) };
+            unsafe { *self.ram.get_unchecked((address & 0x1FFF) as usize) }
         }
         else {
-            return unsafe { *self.rom.prg_rom.get_unchecked((address & 0x3FFF) as usize) };
+            // NROM-128 shows its 16K twice
+            self.rom.prg_rom[(address & 0x7FFF) as usize % self.rom.prg_rom.len()]
         }
     }
     fn load_chr_u8(&self, address: u16) -> u8 {
-        unsafe { *self.rom.chr_rom.get_unchecked(address as usize) }
+        self.rom.load_chr(address as usize)
     }
 
     fn store_prg_u8(&mut self, address: u16, value: u8) {
@@ -50,14 +266,2013 @@ impl Mapper for Mapper0 {
     }
 
     fn store_chr_u8(&mut self, address: u16, value: u8) {
-//      panic!("Mapper0::store_chr_u8");
-        unsafe { *self.rom.chr_rom.get_unchecked_mut(address as usize) = value; }
+        self.rom.store_chr(address as usize, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        rom_mirroring(&self.rom)
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        battery_ram(&self.rom, &mut self.ram)
+    }
+}
+
+fn rom_mirroring(rom: &rom::NesRom) -> [u16; 4] {
+    match rom.mirroring {
+        rom::Mirroring::Horizontal => MIRRORING_HORIZONTAL,
+        rom::Mirroring::Vertical => MIRRORING_VERTICAL,
+        rom::Mirroring::FourScreen => MIRRORING_FOUR_SCREEN,
+        rom::Mirroring::SingleScreenLow => MIRRORING_SINGLE_LOW,
+        rom::Mirroring::SingleScreenHigh => MIRRORING_SINGLE_HIGH
+    }
+}
+
+// NES 2.0 submappers 1 and 2 of the discrete boards say whether there are bus conflicts
+fn bus_conflicts(rom: &rom::NesRom, default: bool) -> bool {
+    match rom.submapper {
+        1 => false,
+        2 => true,
+        _ => default
+    }
+}
+
+fn battery_ram<'a>(rom: &rom::NesRom, ram: &'a mut [u8]) -> Option<&'a mut [u8]> {
+    if rom.has_battery() { Some(ram) } else { None }
+}
+
+// number of banks of the given size, rounded up to at least one
+fn bank_count(len: usize, bank_size: usize) -> usize {
+    std::cmp::max(len / bank_size, 1)
+}
+
+// UxROM: 16K switchable bank at $8000, last 16K bank fixed at $C000, CHR-RAM
+struct Mapper2 {
+    rom: Box<rom::NesRom>,
+    prg_bank: usize,
+    prg_banks: usize,
+    bus_conflicts: bool
+}
+
+impl Mapper2 {
+    fn new(rom: rom::NesRom) -> Mapper2 {
+        let prg_banks = bank_count(rom.prg_rom.len(), 0x4000);
+        let bus_conflicts = bus_conflicts(&rom, true);
+        Mapper2 {
+            rom: Box::new(rom),
+            prg_bank: 0,
+            prg_banks,
+            bus_conflicts
+        }
+    }
+}
+
+impl Mapper for Mapper2 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        let bank = if address < 0xC000 { self.prg_bank } else { self.prg_banks - 1 };
+        self.rom.prg_rom[(bank * 0x4000 + (address & 0x3FFF) as usize) % self.rom.prg_rom.len()]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr((address & 0x1FFF) as usize)
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            // the ROM drives the data bus too, so the written value gets ANDed with it
+            let value = if self.bus_conflicts { value & self.load_prg_u8(address) } else { value };
+            self.prg_bank = value as usize % self.prg_banks;
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        self.rom.store_chr((address & 0x1FFF) as usize, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        rom_mirroring(&self.rom)
+    }
+}
+
+// CNROM: fixed PRG, 8K switchable CHR-ROM bank
+struct Mapper3 {
+    rom: Box<rom::NesRom>,
+    chr_bank: usize,
+    chr_banks: usize,
+    bus_conflicts: bool
+}
+
+impl Mapper3 {
+    fn new(rom: rom::NesRom) -> Mapper3 {
+        let chr_banks = bank_count(rom.chr_len(), 0x2000);
+        let bus_conflicts = bus_conflicts(&rom, true);
+        Mapper3 {
+            rom: Box::new(rom),
+            chr_bank: 0,
+            chr_banks,
+            bus_conflicts
+        }
+    }
+}
+
+impl Mapper for Mapper3 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        self.rom.prg_rom[(address & 0x7FFF) as usize % self.rom.prg_rom.len()]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr(self.chr_bank * 0x2000 + (address & 0x1FFF) as usize)
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            let value = if self.bus_conflicts { value & self.load_prg_u8(address) } else { value };
+            self.chr_bank = value as usize % self.chr_banks;
+        }
+    }
+
+    fn store_chr_u8(&mut self, _address: u16, _value: u8) {
+        // CHR-ROM only
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        rom_mirroring(&self.rom)
+    }
+}
+
+// AxROM: 32K switchable PRG bank, CHR-RAM, single-screen mirroring selected by bit 4
+struct Mapper7 {
+    rom: Box<rom::NesRom>,
+    prg_bank: usize,
+    prg_banks: usize,
+    single_screen: [u16; 4],
+    bus_conflicts: bool
+}
+
+impl Mapper7 {
+    fn new(rom: rom::NesRom) -> Mapper7 {
+        let prg_banks = bank_count(rom.prg_rom.len(), 0x8000);
+        // only ANROM has bus conflicts, AOROM (the common one) doesn't
+        let bus_conflicts = bus_conflicts(&rom, false);
+        Mapper7 {
+            rom: Box::new(rom),
+            prg_bank: prg_banks - 1,
+            prg_banks,
+            single_screen: MIRRORING_SINGLE_LOW,
+            bus_conflicts
+        }
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        let offset = self.prg_bank * 0x8000 + (address & 0x7FFF) as usize;
+        self.rom.prg_rom[offset % self.rom.prg_rom.len()]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr((address & 0x1FFF) as usize)
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            let value = if self.bus_conflicts { value & self.load_prg_u8(address) } else { value };
+            self.prg_bank = (value & 0x07) as usize % self.prg_banks;
+            self.single_screen = if value & 0x10 != 0 {
+                MIRRORING_SINGLE_HIGH
+            }
+            else {
+                MIRRORING_SINGLE_LOW
+            };
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        self.rom.store_chr((address & 0x1FFF) as usize, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        self.single_screen
+    }
+}
+
+// PPU cycles A12 must stay low before a rising edge clocks the MMC3 counter (~3 CPU cycles)
+const MMC3_A12_FILTER: u64 = 10;
+
+// MMC3 (TxROM): 8K PRG banks, 1K/2K CHR banks, scanline counter clocked by PPU A12
+struct Mapper4 {
+    rom: Box<rom::NesRom>,
+    ram: [u8; 0x2000],
+    bank_select: u8,
+    registers: [u8; 8],
+    prg_offsets: [usize; 4],
+    chr_offsets: [usize; 8],
+    mirroring: [u16; 4],
+    ram_enabled: bool,
+    ram_write_protect: bool,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12: bool,
+    a12_low_since: u64
+}
+
+impl Mapper4 {
+    fn new(rom: rom::NesRom) -> Mapper4 {
+        let mirroring = rom_mirroring(&rom);
+        let mut ram = [0; 0x2000];
+        rom.load_trainer(&mut ram);
+        let mut mapper = Mapper4 {
+            rom: Box::new(rom),
+            ram,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            prg_offsets: [0; 4],
+            chr_offsets: [0; 8],
+            mirroring,
+            ram_enabled: true,
+            ram_write_protect: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_since: 0
+        };
+        mapper.update_banks();
+        mapper
+    }
+
+    fn update_banks(&mut self) {
+        let prg_banks = bank_count(self.rom.prg_rom.len(), 0x2000);
+        let chr_banks = bank_count(self.rom.chr_len(), 0x400);
+        let prg = |bank: usize| (bank % prg_banks) * 0x2000;
+        let chr = |bank: u8| (bank as usize % chr_banks) * 0x400;
+
+        let r6 = prg(self.registers[6] as usize);
+        let r7 = prg(self.registers[7] as usize);
+        let second_last = prg(prg_banks.wrapping_sub(2));
+        let last = prg(prg_banks - 1);
+        self.prg_offsets = if self.bank_select & 0x40 == 0 {
+            [r6, r7, second_last, last]
+        }
+        else {
+            [second_last, r7, r6, last]
+        };
+
+        let r = &self.registers;
+        let two_k = [chr(r[0] & 0xFE), chr(r[0] | 1), chr(r[1] & 0xFE), chr(r[1] | 1)];
+        let one_k = [chr(r[2]), chr(r[3]), chr(r[4]), chr(r[5])];
+        let (low, high) = if self.bank_select & 0x80 == 0 { (two_k, one_k) } else { (one_k, two_k) };
+        self.chr_offsets[..4].copy_from_slice(&low);
+        self.chr_offsets[4..].copy_from_slice(&high);
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        }
+        else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mapper4 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        else if address < 0x8000 {
+            return if self.ram_enabled { self.ram[(address & 0x1FFF) as usize] } else { 0 };
+        }
+        let offset = self.prg_offsets[((address - 0x8000) >> 13) as usize];
+        self.rom.prg_rom[(offset + (address & 0x1FFF) as usize) % self.rom.prg_rom.len()]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        let offset = self.chr_offsets[((address >> 10) & 7) as usize];
+        self.rom.load_chr(offset + (address & 0x3FF) as usize)
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if address < 0x6000 {
+            return;
+        }
+        else if address < 0x8000 {
+            if self.ram_enabled && !self.ram_write_protect {
+                self.ram[(address & 0x1FFF) as usize] = value;
+            }
+            return;
+        }
+
+        let even = address & 1 == 0;
+        match (address & 0xE000, even) {
+            (0x8000, true) => {
+                self.bank_select = value;
+                self.update_banks();
+            },
+            (0x8000, false) => {
+                self.registers[(self.bank_select & 0x07) as usize] = value;
+                self.update_banks();
+            },
+            // four-screen boards (TR1ROM) hardwire the nametables
+            (0xA000, true) if self.rom.mirroring != rom::Mirroring::FourScreen => {
+                self.mirroring = if value & 1 == 0 { MIRRORING_VERTICAL } else { MIRRORING_HORIZONTAL };
+            },
+            (0xA000, false) => {
+                self.ram_enabled = value & 0x80 != 0;
+                self.ram_write_protect = value & 0x40 != 0;
+            },
+            (0xC000, true) => {
+                self.irq_latch = value;
+            },
+            (0xC000, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            },
+            (0xE000, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            },
+            (0xE000, false) => {
+                self.irq_enabled = true;
+            },
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offsets[((address >> 10) & 7) as usize];
+        self.rom.store_chr(offset + (address & 0x3FF) as usize, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        self.mirroring
+    }
+
+    fn ppu_address(&mut self, address: u16, cycle: u64) {
+        let a12 = address & 0x1000 != 0;
+        if a12 && !self.a12 {
+            if cycle.wrapping_sub(self.a12_low_since) >= MMC3_A12_FILTER {
+                self.clock_irq_counter();
+            }
+        }
+        else if !a12 && self.a12 {
+            self.a12_low_since = cycle;
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        battery_ram(&self.rom, &mut self.ram)
+    }
+}
+
+// MMC2 (PxROM, mapper 9) and MMC4 (FxROM, mapper 10): two 4K CHR banks per pattern table,
+// picked by latches flipped when the PPU fetches tile $FD or $FE.
+// They only differ in PRG banking: MMC2 switches 8K at $8000, MMC4 switches 16K
+struct Mapper9 {
+    rom: Box<rom::NesRom>,
+    ram: [u8; 0x2000],
+    mmc4: bool,
+    prg_bank: usize,
+    chr_banks: [[usize; 2]; 2],
+    latches: [usize; 2],
+    pending_latch: Option<(usize, usize)>,
+    mirroring: [u16; 4]
+}
+
+impl Mapper9 {
+    fn new(rom: rom::NesRom, mmc4: bool) -> Mapper9 {
+        let mirroring = rom_mirroring(&rom);
+        let mut ram = [0; 0x2000];
+        rom.load_trainer(&mut ram);
+        Mapper9 {
+            rom: Box::new(rom),
+            ram,
+            mmc4,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [1; 2],
+            pending_latch: None,
+            mirroring
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let len = self.rom.prg_rom.len();
+        let (bank_size, fixed_from) = if self.mmc4 { (0x4000, 0xC000) } else { (0x2000, 0xA000) };
+        let offset = if address < fixed_from {
+            self.prg_bank * bank_size + (address as usize & (bank_size - 1))
+        }
+        else {
+            // the rest of the window maps the end of PRG-ROM
+            len - (0x10000 - address as usize) % len
+        };
+        offset % len
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let table = ((address >> 12) & 1) as usize;
+        let bank = self.chr_banks[table][self.latches[table]];
+        (bank * 0x1000 + (address & 0xFFF) as usize) % self.rom.chr_len()
+    }
+}
+
+impl Mapper for Mapper9 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        else if address < 0x8000 {
+            return if self.mmc4 { self.ram[(address & 0x1FFF) as usize] } else { 0 };
+        }
+        self.rom.prg_rom[self.prg_offset(address)]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr(self.chr_offset(address))
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        let bank = (value & 0x1F) as usize;
+        match address & 0xF000 {
+            0x6000 | 0x7000 if self.mmc4 => self.ram[(address & 0x1FFF) as usize] = value,
+            0xA000 => {
+                self.prg_bank = (value & 0x0F) as usize;
+            },
+            0xB000 => self.chr_banks[0][0] = bank,
+            0xC000 => self.chr_banks[0][1] = bank,
+            0xD000 => self.chr_banks[1][0] = bank,
+            0xE000 => self.chr_banks[1][1] = bank,
+            0xF000 => {
+                self.mirroring = if value & 1 == 0 { MIRRORING_VERTICAL } else { MIRRORING_HORIZONTAL };
+            },
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        self.rom.store_chr(offset, value);
     }
 
     fn mirroring(&self) -> [u16; 4] {
-        match self.rom.mirroring {
-            rom::Mirroring::Horizontal => { [0, 0, 0x400, 0x400] },
-            rom::Mirroring::Vertical => { [0, 0x400, 0, 0x400] }
+        self.mirroring
+    }
+
+    fn ppu_address(&mut self, address: u16, _cycle: u64) {
+        // the latch flips right after the triggering fetch, so the $FD/$FE tile itself
+        // still comes from the previous bank
+        if let Some((table, latch)) = self.pending_latch.take() {
+            self.latches[table] = latch;
+        }
+
+        // MMC2 only triggers on $0FD8/$0FE8 for the first table, MMC4 on the whole row range
+        let trigger = match address & 0x1FF8 {
+            0x0FD8 if self.mmc4 || address == 0x0FD8 => Some((0, 0)),
+            0x0FE8 if self.mmc4 || address == 0x0FE8 => Some((0, 1)),
+            0x1FD8 => Some((1, 0)),
+            0x1FE8 => Some((1, 1)),
+            _ => None
+        };
+        if trigger.is_some() {
+            self.pending_latch = trigger;
+        }
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        // only MMC4 boards have PRG-RAM
+        if self.mmc4 { battery_ram(&self.rom, &mut self.ram) } else { None }
+    }
+}
+
+// PPU cycles without VRAM accesses after which MMC5 considers rendering stopped
+const MMC5_IDLE_PPU_CYCLES: u64 = 12;
+// CPU cycles between MMC5 audio envelope/length clocks (240 Hz)
+const MMC5_AUDIO_FRAME_CYCLES: u64 = 7457;
+
+// MMC5 (ExROM)
+struct Mapper5 {
+    rom: Box<rom::NesRom>,
+    ram: Vec<u8>,
+    exram: [u8; 0x400],
+    prg_mode: u8,
+    chr_mode: u8,
+    ram_protect: [u8; 2],
+    exram_mode: u8,
+    nt_mapping: u8,
+    fill_tile: u8,
+    fill_attr: u8,
+    prg_registers: [u8; 5],
+    chr_registers: [u16; 12],
+    chr_upper: u16,
+    last_chr_set_bg: bool,
+    split_control: u8,
+    split_scroll: u8,
+    split_page: u8,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+    // PPU state as seen from the cartridge
+    sprite_16: bool,
+    rendering: bool,
+    in_frame: bool,
+    scanline: u8,
+    last_ppu_cycle: u64,
+    line_start: u64,
+    last_nt_address: u16,
+    nt_repeat: u8,
+    tile: usize,
+    sprite_fetch: bool,
+    exattr_index: usize,
+    split_tile: Option<(usize, usize)>,
+    // audio
+    pulse1: apu::Pulse,
+    pulse2: apu::Pulse,
+    pcm: u8,
+    pcm_read_mode: bool,
+    audio_cycles: u64
+}
+
+impl Mapper5 {
+    fn new(rom: rom::NesRom) -> Mapper5 {
+        let ram_size = rom.prg_ram_len(0x10000);
+        let mut ram = vec![0; ram_size];
+        rom.load_trainer(&mut ram);
+        Mapper5 {
+            rom: Box::new(rom),
+            ram,
+            exram: [0; 0x400],
+            prg_mode: 3,
+            chr_mode: 0,
+            ram_protect: [0; 2],
+            exram_mode: 0,
+            nt_mapping: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_registers: [0, 0, 0, 0, 0xFF],
+            chr_registers: [0; 12],
+            chr_upper: 0,
+            last_chr_set_bg: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_page: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            sprite_16: false,
+            rendering: false,
+            in_frame: false,
+            scanline: 0,
+            last_ppu_cycle: 0,
+            line_start: 0,
+            last_nt_address: 0,
+            nt_repeat: 0,
+            tile: 0,
+            sprite_fetch: false,
+            exattr_index: 0,
+            split_tile: None,
+            pulse1: apu::Pulse::new(),
+            pulse2: apu::Pulse::new(),
+            pcm: 0,
+            pcm_read_mode: false,
+            audio_cycles: 0
+        }
+    }
+
+    // returns (is_rom, offset) for a CPU address in $6000-$FFFF
+    fn prg_offset(&self, address: u16) -> (bool, usize) {
+        if address < 0x8000 {
+            let bank = (self.prg_registers[0] & 0x07) as usize;
+            return (false, bank * 0x2000 + (address & 0x1FFF) as usize);
+        }
+
+        let slot = ((address - 0x8000) >> 13) as usize;
+        // (register, bank size in 8K units) for each 8K slot
+        let (register, size) = match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1, 0) | (1, 1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0) | (2, 1) => (2, 2),
+            (2, 2) => (3, 1),
+            (_, _) => (slot + 1, 1)
+        };
+        let value = self.prg_registers[register];
+        // $5117 always maps ROM
+        let is_rom = register == 4 || value & 0x80 != 0;
+        let bank = (value & 0x7F) as usize & !(size - 1);
+        let offset = bank * 0x2000 + (address as usize & (size * 0x2000 - 1));
+        (is_rom, offset)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let address = address as usize & 0x1FFF;
+
+        // extended attributes and split screen pick their own 4K page for the background
+        if self.in_frame && !self.sprite_fetch {
+            if let Some((y, _)) = self.split_tile {
+                return self.split_page as usize * 0x1000 + (address & 0xFF8) + (y & 7);
+            }
+            if self.exram_mode == 1 {
+                let bank = (self.exram[self.exattr_index] & 0x3F) as usize | ((self.chr_upper as usize) << 6);
+                return bank * 0x1000 + (address & 0xFFF);
+            }
         }
+
+        let use_bg_set = if self.sprite_16 && self.rendering && self.in_frame {
+            !self.sprite_fetch
+        }
+        else if !self.sprite_16 {
+            false
+        }
+        else {
+            self.last_chr_set_bg
+        };
+
+        let size = 0x2000 >> self.chr_mode;
+        let index = address / size;
+        let register = if use_bg_set {
+            // $5128-$512B only cover 4K, repeated in both pattern tables
+            match self.chr_mode {
+                0 | 1 => 11,
+                2 => 9 + (index & 1) * 2,
+                _ => 8 + (index & 3)
+            }
+        }
+        else {
+            // the last register of each group of 8 >> mode
+            let step = 8 >> self.chr_mode;
+            index * step + step - 1
+        };
+        self.chr_registers[register] as usize * size + (address & (size - 1))
     }
-}
\ No newline at end of file
+
+    fn nt_source(&self, address: u16) -> u8 {
+        (self.nt_mapping >> (((address >> 10) & 3) * 2)) & 3
+    }
+
+    fn scanline_detected(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare {
+                self.irq_pending = true;
+            }
+        }
+        else {
+            self.in_frame = true;
+            self.scanline = 0;
+        }
+        self.tile = 2;
+    }
+
+    fn split_fetch(&self) -> Option<(usize, usize)> {
+        if !self.in_frame || self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return None;
+        }
+        // the two tiles fetched at the end of a line belong to the next one
+        let (tile, line) = if self.tile >= 34 {
+            (self.tile - 34, self.scanline as usize + 1)
+        }
+        else {
+            (self.tile, self.scanline as usize)
+        };
+        let threshold = (self.split_control & 0x1F) as usize;
+        let inside = if self.split_control & 0x40 == 0 { tile < threshold } else { tile >= threshold };
+        if !inside {
+            return None;
+        }
+        let y = (self.split_scroll as usize + line) % 240;
+        Some((y, tile & 0x1F))
+    }
+}
+
+impl Mapper for Mapper5 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        match address {
+            0x5015 => {
+                self.pulse1.length_active() as u8 | ((self.pulse2.length_active() as u8) << 1)
+            },
+            0x5204 => {
+                let status = ((self.irq_pending as u8) << 7) | ((self.in_frame as u8) << 6);
+                self.irq_pending = false;
+                status
+            },
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[(address & 0x3FF) as usize],
+            0x6000..=0xFFFF => {
+                if address == 0xFFFA || address == 0xFFFB {
+                    // the NMI vector fetch marks the end of the frame
+                    self.in_frame = false;
+                }
+                let (is_rom, offset) = self.prg_offset(address);
+                let value = if is_rom {
+                    self.rom.prg_rom[offset % self.rom.prg_rom.len()]
+                }
+                else {
+                    self.ram[offset % self.ram.len()]
+                };
+                if self.pcm_read_mode && (0x8000..0xC000).contains(&address) && value != 0 {
+                    self.pcm = value;
+                }
+                value
+            },
+            _ => 0
+        }
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr(self.chr_offset(address) % self.rom.chr_len())
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000 => self.pulse1.write4000(value),
+            0x5002 => self.pulse1.write4002(value),
+            0x5003 => self.pulse1.write4003(value),
+            0x5004 => self.pulse2.write4000(value),
+            0x5006 => self.pulse2.write4002(value),
+            0x5007 => self.pulse2.write4003(value),
+            0x5010 => self.pcm_read_mode = value & 1 != 0,
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulse1.set_channel_enabled(value & 1 != 0);
+                self.pulse2.set_channel_enabled(value & 2 != 0);
+            },
+            0x5100 => self.prg_mode = value & 3,
+            0x5101 => self.chr_mode = value & 3,
+            0x5102 => self.ram_protect[0] = value & 3,
+            0x5103 => self.ram_protect[1] = value & 3,
+            0x5104 => self.exram_mode = value & 3,
+            0x5105 => self.nt_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attr = value & 3,
+            0x5113..=0x5117 => self.prg_registers[(address - 0x5113) as usize] = value,
+            0x5120..=0x512B => {
+                let index = (address - 0x5120) as usize;
+                self.chr_registers[index] = value as u16 | (self.chr_upper << 8);
+                self.last_chr_set_bg = index >= 8;
+            },
+            0x5130 => self.chr_upper = (value & 3) as u16,
+            0x5200 => self.split_control = value,
+            0x5201 => self.split_scroll = value,
+            0x5202 => self.split_page = value,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            0x5C00..=0x5FFF if self.exram_mode != 3 => self.exram[(address & 0x3FF) as usize] = value,
+            0x6000..=0xFFFF => {
+                let (is_rom, offset) = self.prg_offset(address);
+                if !is_rom && self.ram_protect == [0b10, 0b01] {
+                    let len = self.ram.len();
+                    self.ram[offset % len] = value;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let len = self.rom.chr_len();
+        let offset = self.chr_offset(address) % len;
+        self.rom.store_chr(offset, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        let mut mirroring = [0; 4];
+        for (i, nt) in mirroring.iter_mut().enumerate() {
+            *nt = if (self.nt_mapping >> (i * 2)) & 3 == 1 { 0x400 } else { 0 };
+        }
+        mirroring
+    }
+
+    fn load_nt_u8(&self, address: u16, vram: &[u8]) -> u8 {
+        let index = (address & 0x3FF) as usize;
+        let attribute = index >= 0x3C0;
+
+        if self.in_frame {
+            if let Some((y, tile)) = self.split_tile {
+                if !attribute {
+                    return self.exram[(y / 8) * 32 + tile];
+                }
+                let at = self.exram[0x3C0 + (y / 32) * 8 + tile / 4];
+                let shift = ((y / 16) & 1) * 4 + ((tile / 2) & 1) * 2;
+                return ((at >> shift) & 3) * 0x55;
+            }
+            if attribute && self.exram_mode == 1 {
+                return (self.exram[self.exattr_index] >> 6) * 0x55;
+            }
+        }
+
+        match self.nt_source(address) {
+            0 => vram[index],
+            1 => vram[0x400 + index],
+            2 => if self.exram_mode <= 1 { self.exram[index] } else { 0 },
+            _ => if attribute { self.fill_attr * 0x55 } else { self.fill_tile }
+        }
+    }
+
+    fn store_nt_u8(&mut self, address: u16, value: u8, vram: &mut [u8]) {
+        let index = (address & 0x3FF) as usize;
+        match self.nt_source(address) {
+            0 => vram[index] = value,
+            1 => vram[0x400 + index] = value,
+            2 if self.exram_mode <= 1 => self.exram[index] = value,
+            _ => {}
+        }
+    }
+
+    fn ppu_address(&mut self, address: u16, cycle: u64) {
+        if cycle.wrapping_sub(self.last_ppu_cycle) > MMC5_IDLE_PPU_CYCLES {
+            self.in_frame = false;
+        }
+        self.last_ppu_cycle = cycle;
+
+        // three reads in a row from the same nametable address mark the start of a scanline
+        if (0x2000..0x3000).contains(&address) && (address & 0x3FF) < 0x3C0 {
+            if address == self.last_nt_address {
+                self.nt_repeat += 1;
+                if self.nt_repeat == 2 {
+                    self.scanline_detected();
+                    self.line_start = cycle;
+                }
+            }
+            else {
+                self.nt_repeat = 0;
+                self.tile += 1;
+            }
+            self.last_nt_address = address;
+            self.exattr_index = (address & 0x3FF) as usize;
+            self.split_tile = self.split_fetch();
+        }
+
+        let dot = cycle.wrapping_sub(self.line_start);
+        self.sprite_fetch = (256..320).contains(&dot);
+    }
+
+    fn ppu_register_write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.sprite_16 = value & 0x20 != 0,
+            1 => {
+                self.rendering = value & 0x18 != 0;
+                if !self.rendering {
+                    self.in_frame = false;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.audio_cycles += 1;
+            if self.audio_cycles.is_multiple_of(2) {
+                self.pulse1.step();
+                self.pulse2.step();
+            }
+            if self.audio_cycles.is_multiple_of(MMC5_AUDIO_FRAME_CYCLES) {
+                self.pulse1.decay();
+                self.pulse1.length();
+                self.pulse2.decay();
+                self.pulse2.length();
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn audio_out(&self) -> u8 {
+        self.pulse1.out() + self.pulse2.out() + (self.pcm >> 3)
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        battery_ram(&self.rom, &mut self.ram)
+    }
+}
+
+// IRQ counter shared by the Konami VRC chips, clocked either every CPU cycle
+// or once per scanline through a prescaler that divides CPU cycles by 113.667
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool
+}
+
+impl VrcIrq {
+    fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        self.enable_after_ack = value & 1 != 0;
+        self.enabled = value & 2 != 0;
+        self.cycle_mode = value & 4 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        }
+        else {
+            self.counter += 1;
+        }
+    }
+
+    fn run(&mut self, cycles: u64) {
+        if !self.enabled {
+            return;
+        }
+        for _ in 0..cycles {
+            if self.cycle_mode {
+                self.clock_counter();
+            }
+            else {
+                self.prescaler -= 3;
+                if self.prescaler <= 0 {
+                    self.prescaler += 341;
+                    self.clock_counter();
+                }
+            }
+        }
+    }
+}
+
+// VRC2/VRC4 (mappers 21, 22, 23 and 25). The boards differ in which CPU address lines
+// select the register inside each $1000 page: NES 2.0 submappers tell them apart,
+// otherwise the lines of all the variants sharing a mapper number are ORed together
+struct Mapper21 {
+    rom: Box<rom::NesRom>,
+    ram: [u8; 0x2000],
+    mapper_id: u16,
+    submapper: u8,
+    prg_banks: [usize; 2],
+    prg_swap: bool,
+    chr_banks: [usize; 8],
+    mirroring: [u16; 4],
+    irq: VrcIrq
+}
+
+impl Mapper21 {
+    fn new(rom: rom::NesRom) -> Mapper21 {
+        let mirroring = rom_mirroring(&rom);
+        let mapper_id = rom.mapper_id;
+        let submapper = rom.submapper;
+        let mut ram = [0; 0x2000];
+        rom.load_trainer(&mut ram);
+        Mapper21 {
+            rom: Box::new(rom),
+            ram,
+            mapper_id,
+            submapper,
+            prg_banks: [0, 1],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring,
+            irq: VrcIrq::new()
+        }
+    }
+
+    // returns the register number (0-3) inside the $1000 page
+    fn register(&self, address: u16) -> u16 {
+        let line = |n: u16| (address >> n) & 1;
+        let (a0, a1) = match (self.mapper_id, self.submapper) {
+            (21, 1) => (line(1), line(2)),
+            (21, 2) => (line(6), line(7)),
+            (21, _) => (line(1) | line(6), line(2) | line(7)),
+            (22, _) => (line(1), line(0)),
+            (23, 1) | (23, 3) => (line(0), line(1)),
+            (23, 2) => (line(2), line(3)),
+            (23, _) => (line(0) | line(2), line(1) | line(3)),
+            (_, 1) | (_, 3) => (line(1), line(0)),
+            (_, 2) => (line(3), line(2)),
+            (_, _) => (line(1) | line(3), line(0) | line(2))
+        };
+        a0 | (a1 << 1)
+    }
+
+    // VRC2 boards, as far as the header tells them apart from VRC4
+    fn is_vrc2(&self) -> bool {
+        matches!((self.mapper_id, self.submapper), (22, _) | (23, 3) | (25, 3))
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let banks = bank_count(self.rom.prg_rom.len(), 0x2000);
+        let second_last = banks.saturating_sub(2);
+        let bank = match (address >> 13) & 3 {
+            0 => if self.prg_swap { second_last } else { self.prg_banks[0] },
+            1 => self.prg_banks[1],
+            2 => if self.prg_swap { self.prg_banks[0] } else { second_last },
+            _ => banks - 1
+        };
+        ((bank % banks) * 0x2000 + (address & 0x1FFF) as usize) % self.rom.prg_rom.len()
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let mut bank = self.chr_banks[((address >> 10) & 7) as usize];
+        if self.mapper_id == 22 {
+            // VRC2a ignores the lowest bank bit
+            bank >>= 1;
+        }
+        (bank * 0x400 + (address & 0x3FF) as usize) % self.rom.chr_len()
+    }
+}
+
+impl Mapper for Mapper21 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        else if address < 0x8000 {
+            return self.ram[(address & 0x1FFF) as usize];
+        }
+        self.rom.prg_rom[self.prg_offset(address)]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr(self.chr_offset(address))
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if address < 0x6000 {
+            return;
+        }
+        else if address < 0x8000 {
+            self.ram[(address & 0x1FFF) as usize] = value;
+            return;
+        }
+
+        let register = self.register(address);
+        match address & 0xF000 {
+            0x8000 => self.prg_banks[0] = (value & 0x1F) as usize,
+            0x9000 => {
+                if self.is_vrc2() {
+                    // only H/V control, and no PRG swap mode
+                    self.mirroring = if value & 1 == 0 { MIRRORING_VERTICAL } else { MIRRORING_HORIZONTAL };
+                }
+                else if register == 0 {
+                    self.mirroring = match value & 3 {
+                        0 => MIRRORING_VERTICAL,
+                        1 => MIRRORING_HORIZONTAL,
+                        2 => MIRRORING_SINGLE_LOW,
+                        _ => MIRRORING_SINGLE_HIGH
+                    };
+                }
+                else if register == 2 {
+                    self.prg_swap = value & 2 != 0;
+                }
+            },
+            0xA000 => self.prg_banks[1] = (value & 0x1F) as usize,
+            0xB000..=0xE000 => {
+                // each 1K bank is written as a low and a high nibble
+                let index = (((address & 0xF000) - 0xB000) >> 11) as usize | (register >> 1) as usize;
+                let bank = &mut self.chr_banks[index];
+                *bank = if register & 1 == 0 {
+                    (*bank & 0x1F0) | (value & 0x0F) as usize
+                }
+                else {
+                    (*bank & 0x0F) | (((value & 0x1F) as usize) << 4)
+                };
+            },
+            0xF000 => match register {
+                0 => self.irq.latch = (self.irq.latch & 0xF0) | (value & 0x0F),
+                1 => self.irq.latch = (self.irq.latch & 0x0F) | (value << 4),
+                2 => self.irq.write_control(value),
+                _ => self.irq.acknowledge()
+            },
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        self.rom.store_chr(offset, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        self.mirroring
+    }
+
+    fn run(&mut self, cycles: u64) {
+        self.irq.run(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        battery_ram(&self.rom, &mut self.ram)
+    }
+}
+
+// VRC6 pulse channel
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    constant: bool,
+    period: u16,
+    enabled: bool,
+    counter: u16,
+    step: u8
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            volume: 0,
+            duty: 0,
+            constant: false,
+            period: 0,
+            enabled: false,
+            counter: 0,
+            step: 0
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.volume = value & 0x0F;
+                self.duty = (value >> 4) & 0x07;
+                self.constant = value & 0x80 != 0;
+            },
+            1 => self.period = (self.period & 0xF00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x0FF) | (((value & 0x0F) as u16) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.counter == 0 {
+            self.counter = self.period;
+            self.step = (self.step + 1) & 0x0F;
+        }
+        else {
+            self.counter -= 1;
+        }
+    }
+
+    fn out(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) { self.volume } else { 0 }
+    }
+}
+
+// VRC6 sawtooth channel
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    counter: u16,
+    step: u8,
+    accumulator: u8
+}
+
+impl Vrc6Saw {
+    fn new() -> Vrc6Saw {
+        Vrc6Saw {
+            rate: 0,
+            period: 0,
+            enabled: false,
+            counter: 0,
+            step: 0,
+            accumulator: 0
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3F,
+            1 => self.period = (self.period & 0xF00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x0FF) | (((value & 0x0F) as u16) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.accumulator = 0;
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.counter > 0 {
+            self.counter -= 1;
+            return;
+        }
+        self.counter = self.period;
+        // the accumulator grows every other clock and resets after 7 additions
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        }
+        else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn out(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// VRC6 (mappers 24 and 26, the latter with A0 and A1 swapped)
+struct Mapper24 {
+    rom: Box<rom::NesRom>,
+    ram: [u8; 0x2000],
+    swap_lines: bool,
+    prg_16k: usize,
+    prg_8k: usize,
+    chr_banks: [usize; 8],
+    mirroring: [u16; 4],
+    ram_enabled: bool,
+    irq: VrcIrq,
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    halt: bool
+}
+
+impl Mapper24 {
+    fn new(rom: rom::NesRom) -> Mapper24 {
+        let mirroring = rom_mirroring(&rom);
+        let swap_lines = rom.mapper_id == 26;
+        let mut ram = [0; 0x2000];
+        rom.load_trainer(&mut ram);
+        Mapper24 {
+            rom: Box::new(rom),
+            ram,
+            swap_lines,
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            mirroring,
+            ram_enabled: false,
+            irq: VrcIrq::new(),
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            saw: Vrc6Saw::new(),
+            halt: false
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.chr_banks[((address >> 10) & 7) as usize];
+        (bank * 0x400 + (address & 0x3FF) as usize) % self.rom.chr_len()
+    }
+}
+
+impl Mapper for Mapper24 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        else if address < 0x8000 {
+            return if self.ram_enabled { self.ram[(address & 0x1FFF) as usize] } else { 0 };
+        }
+        let len = self.rom.prg_rom.len();
+        let offset = if address < 0xC000 {
+            self.prg_16k * 0x4000 + (address & 0x3FFF) as usize
+        }
+        else if address < 0xE000 {
+            self.prg_8k * 0x2000 + (address & 0x1FFF) as usize
+        }
+        else {
+            len - 0x2000 + (address & 0x1FFF) as usize
+        };
+        self.rom.prg_rom[offset % len]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr(self.chr_offset(address))
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if address < 0x6000 {
+            return;
+        }
+        else if address < 0x8000 {
+            if self.ram_enabled {
+                self.ram[(address & 0x1FFF) as usize] = value;
+            }
+            return;
+        }
+
+        let register = if self.swap_lines {
+            ((address & 1) << 1) | ((address >> 1) & 1)
+        }
+        else {
+            address & 3
+        };
+        match (address & 0xF000, register) {
+            (0x8000, _) => self.prg_16k = (value & 0x0F) as usize,
+            (0x9000, 3) => {
+                self.halt = value & 1 != 0;
+            },
+            (0x9000, _) => self.pulse1.write(register, value),
+            (0xA000, 3) => {},
+            (0xA000, _) => self.pulse2.write(register, value),
+            (0xB000, 3) => {
+                self.ram_enabled = value & 0x80 != 0;
+                self.mirroring = match (value >> 2) & 3 {
+                    0 => MIRRORING_VERTICAL,
+                    1 => MIRRORING_HORIZONTAL,
+                    2 => MIRRORING_SINGLE_LOW,
+                    _ => MIRRORING_SINGLE_HIGH
+                };
+            },
+            (0xB000, _) => self.saw.write(register, value),
+            (0xC000, _) => self.prg_8k = (value & 0x1F) as usize,
+            (0xD000, _) => self.chr_banks[register as usize] = value as usize,
+            (0xE000, _) => self.chr_banks[4 + register as usize] = value as usize,
+            (0xF000, 0) => self.irq.latch = value,
+            (0xF000, 1) => self.irq.write_control(value),
+            (0xF000, 2) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        self.rom.store_chr(offset, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        self.mirroring
+    }
+
+    fn run(&mut self, cycles: u64) {
+        self.irq.run(cycles);
+        if !self.halt {
+            for _ in 0..cycles {
+                self.pulse1.step();
+                self.pulse2.step();
+                self.saw.step();
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn audio_out(&self) -> u8 {
+        self.pulse1.out() + self.pulse2.out() + self.saw.out()
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        battery_ram(&self.rom, &mut self.ram)
+    }
+}
+
+// Sunsoft FME-7 (mapper 69): command/parameter register pair, banked PRG-RAM and a
+// 16-bit IRQ counter decremented every CPU cycle
+struct Mapper69 {
+    rom: Box<rom::NesRom>,
+    ram: Vec<u8>,
+    command: u8,
+    prg_banks: [usize; 3],
+    ram_bank: usize,
+    ram_selected: bool,
+    ram_enabled: bool,
+    chr_banks: [usize; 8],
+    mirroring: [u16; 4],
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool
+}
+
+impl Mapper69 {
+    fn new(rom: rom::NesRom) -> Mapper69 {
+        let mirroring = rom_mirroring(&rom);
+        let ram_size = rom.prg_ram_len(0x2000);
+        let mut ram = vec![0; ram_size];
+        rom.load_trainer(&mut ram);
+        Mapper69 {
+            rom: Box::new(rom),
+            ram,
+            command: 0,
+            prg_banks: [0; 3],
+            ram_bank: 0,
+            ram_selected: false,
+            ram_enabled: false,
+            chr_banks: [0; 8],
+            mirroring,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false
+        }
+    }
+
+    fn prg_rom_u8(&self, bank: usize, address: u16) -> u8 {
+        let offset = bank * 0x2000 + (address & 0x1FFF) as usize;
+        self.rom.prg_rom[offset % self.rom.prg_rom.len()]
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.chr_banks[((address >> 10) & 7) as usize];
+        (bank * 0x400 + (address & 0x3FF) as usize) % self.rom.chr_len()
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0..=7 => self.chr_banks[self.command as usize] = value as usize,
+            8 => {
+                self.ram_bank = (value & 0x3F) as usize;
+                self.ram_selected = value & 0x40 != 0;
+                self.ram_enabled = value & 0x80 != 0;
+            },
+            9..=0x0B => self.prg_banks[(self.command - 9) as usize] = (value & 0x3F) as usize,
+            0x0C => {
+                self.mirroring = match value & 3 {
+                    0 => MIRRORING_VERTICAL,
+                    1 => MIRRORING_HORIZONTAL,
+                    2 => MIRRORING_SINGLE_LOW,
+                    _ => MIRRORING_SINGLE_HIGH
+                };
+            },
+            0x0D => {
+                self.irq_enabled = value & 1 != 0;
+                self.irq_counter_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            },
+            0x0E => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | ((value as u16) << 8)
+        }
+    }
+}
+
+impl Mapper for Mapper69 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        else if address < 0x8000 {
+            if !self.ram_selected {
+                return self.prg_rom_u8(self.ram_bank, address);
+            }
+            else if self.ram_enabled {
+                let offset = self.ram_bank * 0x2000 + (address & 0x1FFF) as usize;
+                return self.ram[offset % self.ram.len()];
+            }
+            return 0;
+        }
+        let bank = match address {
+            0x8000..=0x9FFF => self.prg_banks[0],
+            0xA000..=0xBFFF => self.prg_banks[1],
+            0xC000..=0xDFFF => self.prg_banks[2],
+            _ => bank_count(self.rom.prg_rom.len(), 0x2000) - 1
+        };
+        self.prg_rom_u8(bank, address)
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr(self.chr_offset(address))
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.ram_selected && self.ram_enabled => {
+                let len = self.ram.len();
+                self.ram[(self.ram_bank * 0x2000 + (address & 0x1FFF) as usize) % len] = value;
+            },
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(value),
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        self.rom.store_chr(offset, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        self.mirroring
+    }
+
+    fn run(&mut self, cycles: u64) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+        for _ in 0..cycles {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        battery_ram(&self.rom, &mut self.ram)
+    }
+}
+
+// CPU cycles spent by the Namco 163 updating each wavetable channel
+const N163_CHANNEL_CYCLES: u64 = 15;
+
+// Namco 163 (mapper 19): 1K CHR banks that can also map the nametable RAM, nametables
+// that can map CHR-ROM, a 15-bit IRQ counter and 128 bytes of internal RAM shared by
+// the wavetable sound channels
+struct Mapper19 {
+    rom: Box<rom::NesRom>,
+    ram: [u8; 0x2000],
+    // the 163 decides what the PPU sees in the nametables, so it keeps CIRAM itself
+    ciram: [u8; 0x800],
+    chr_banks: [u8; 8],
+    nt_banks: [u8; 4],
+    prg_banks: [usize; 3],
+    chr_ram_disabled: [bool; 2],
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+    sound_ram: [u8; 0x80],
+    sound_address: u8,
+    sound_increment: bool,
+    sound_disabled: bool,
+    sound_cycles: u64,
+    sound_channel: usize,
+    channel_out: [i16; 8]
+}
+
+impl Mapper19 {
+    fn new(rom: rom::NesRom) -> Mapper19 {
+        let mut ram = [0; 0x2000];
+        rom.load_trainer(&mut ram);
+        Mapper19 {
+            rom: Box::new(rom),
+            ram,
+            ciram: [0; 0x800],
+            chr_banks: [0; 8],
+            nt_banks: [0xE0; 4],
+            prg_banks: [0; 3],
+            chr_ram_disabled: [false; 2],
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            sound_ram: [0; 0x80],
+            sound_address: 0,
+            sound_increment: false,
+            sound_disabled: true,
+            sound_cycles: 0,
+            sound_channel: 0,
+            channel_out: [0; 8]
+        }
+    }
+
+    // a 1K bank number either maps CHR-ROM or, from $E0 up, one of the CIRAM pages
+    fn bank_u8(&self, bank: u8, allow_ciram: bool, address: u16) -> u8 {
+        if allow_ciram && bank >= 0xE0 {
+            self.ciram[(bank as usize & 1) * 0x400 + (address & 0x3FF) as usize]
+        }
+        else {
+            let offset = bank as usize * 0x400 + (address & 0x3FF) as usize;
+            self.rom.load_chr(offset % self.rom.chr_len())
+        }
+    }
+
+    fn sound_data(&mut self) -> &mut u8 {
+        let address = self.sound_address as usize;
+        if self.sound_increment {
+            self.sound_address = (self.sound_address + 1) & 0x7F;
+        }
+        &mut self.sound_ram[address]
+    }
+
+    fn sample(&self, index: usize) -> i16 {
+        let value = self.sound_ram[(index >> 1) & 0x7F];
+        (if index & 1 == 0 { value & 0x0F } else { value >> 4 }) as i16
+    }
+
+    fn active_channels(&self) -> usize {
+        (((self.sound_ram[0x7F] >> 4) & 7) + 1) as usize
+    }
+
+    fn update_channel(&mut self) {
+        let count = self.active_channels();
+        self.sound_channel = (self.sound_channel + 1) % count;
+        // channel 7 lives at $78, channel 0 at $40
+        let base = 0x78 - self.sound_channel * 8;
+        let regs = &self.sound_ram[base..base + 8];
+        let frequency = regs[0] as u32 | ((regs[2] as u32) << 8) | (((regs[4] & 3) as u32) << 16);
+        let length = 256 - (regs[4] & 0xFC) as u32;
+        let offset = regs[6] as u32;
+        let volume = (regs[7] & 0x0F) as i16;
+        let mut phase = regs[1] as u32 | ((regs[3] as u32) << 8) | ((regs[5] as u32) << 16);
+
+        phase = (phase + frequency) % (length << 16);
+        let sample = self.sample(((phase >> 16) + offset) as usize);
+        self.channel_out[self.sound_channel] = (sample - 8) * volume;
+
+        self.sound_ram[base + 1] = phase as u8;
+        self.sound_ram[base + 3] = (phase >> 8) as u8;
+        self.sound_ram[base + 5] = (phase >> 16) as u8;
+    }
+}
+
+impl Mapper for Mapper19 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => *self.sound_data(),
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => ((self.irq_counter >> 8) as u8) | ((self.irq_enabled as u8) << 7),
+            0x6000..=0x7FFF => self.ram[(address & 0x1FFF) as usize],
+            0x8000..=0xFFFF => {
+                let bank = match address {
+                    0x8000..=0x9FFF => self.prg_banks[0],
+                    0xA000..=0xBFFF => self.prg_banks[1],
+                    0xC000..=0xDFFF => self.prg_banks[2],
+                    _ => bank_count(self.rom.prg_rom.len(), 0x2000) - 1
+                };
+                let offset = bank * 0x2000 + (address & 0x1FFF) as usize;
+                self.rom.prg_rom[offset % self.rom.prg_rom.len()]
+            },
+            _ => 0
+        }
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        let half = ((address >> 12) & 1) as usize;
+        let bank = self.chr_banks[((address >> 10) & 7) as usize];
+        self.bank_u8(bank, !self.chr_ram_disabled[half], address)
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        match address {
+            0x4800..=0x4FFF => *self.sound_data() = value,
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            },
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (((value & 0x7F) as u16) << 8);
+                self.irq_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            },
+            0x6000..=0x7FFF => self.ram[(address & 0x1FFF) as usize] = value,
+            0x8000..=0xBFFF => self.chr_banks[((address - 0x8000) >> 11) as usize] = value,
+            0xC000..=0xDFFF => self.nt_banks[((address - 0xC000) >> 11) as usize] = value,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = (value & 0x3F) as usize;
+                self.sound_disabled = value & 0x40 != 0;
+            },
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = (value & 0x3F) as usize;
+                self.chr_ram_disabled = [value & 0x40 != 0, value & 0x80 != 0];
+            },
+            0xF000..=0xF7FF => self.prg_banks[2] = (value & 0x3F) as usize,
+            0xF800..=0xFFFF => {
+                self.sound_address = value & 0x7F;
+                self.sound_increment = value & 0x80 != 0;
+            },
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let half = ((address >> 12) & 1) as usize;
+        let bank = self.chr_banks[((address >> 10) & 7) as usize];
+        if bank >= 0xE0 && !self.chr_ram_disabled[half] {
+            self.ciram[(bank as usize & 1) * 0x400 + (address & 0x3FF) as usize] = value;
+        }
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        let mut mirroring = [0; 4];
+        for (nt, bank) in mirroring.iter_mut().zip(self.nt_banks.iter()) {
+            *nt = (*bank as u16 & 1) * 0x400;
+        }
+        mirroring
+    }
+
+    fn load_nt_u8(&self, address: u16, _vram: &[u8]) -> u8 {
+        let bank = self.nt_banks[((address >> 10) & 3) as usize];
+        self.bank_u8(bank, true, address)
+    }
+
+    fn store_nt_u8(&mut self, address: u16, value: u8, _vram: &mut [u8]) {
+        let bank = self.nt_banks[((address >> 10) & 3) as usize];
+        if bank >= 0xE0 {
+            self.ciram[(bank as usize & 1) * 0x400 + (address & 0x3FF) as usize] = value;
+        }
+    }
+
+    fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            if self.irq_enabled && self.irq_counter < 0x7FFF {
+                self.irq_counter += 1;
+                if self.irq_counter == 0x7FFF {
+                    self.irq_pending = true;
+                }
+            }
+
+            if !self.sound_disabled {
+                self.sound_cycles += 1;
+                if self.sound_cycles.is_multiple_of(N163_CHANNEL_CYCLES) {
+                    self.update_channel();
+                }
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_out(&self) -> u8 {
+        if self.sound_disabled {
+            return 0;
+        }
+        let count = self.active_channels();
+        // the channels are time-multiplexed, so the average is what reaches the speaker
+        let sum: i16 = self.channel_out[..count].iter().sum();
+        ((sum / count as i16 + 120) / 8) as u8
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        battery_ram(&self.rom, &mut self.ram)
+    }
+}
+
+// Single latch selecting a 32K PRG bank and an 8K CHR bank: GxROM (66),
+// Color Dreams (11) and NINA-03/06 (79) only differ in where the bits and the latch are
+struct Mapper66 {
+    rom: Box<rom::NesRom>,
+    mapper_id: u16,
+    prg_bank: usize,
+    chr_bank: usize
+}
+
+impl Mapper66 {
+    fn new(rom: rom::NesRom) -> Mapper66 {
+        let mapper_id = rom.mapper_id;
+        Mapper66 {
+            rom: Box::new(rom),
+            mapper_id,
+            prg_bank: 0,
+            chr_bank: 0
+        }
+    }
+}
+
+impl Mapper for Mapper66 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        let offset = self.prg_bank * 0x8000 + (address & 0x7FFF) as usize;
+        self.rom.prg_rom[offset % self.rom.prg_rom.len()]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        let offset = self.chr_bank * 0x2000 + (address & 0x1FFF) as usize;
+        self.rom.load_chr(offset % self.rom.chr_len())
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        match self.mapper_id {
+            // NINA-03/06 decode the register in $4100-$5FFF, no bus conflicts there
+            79 if address < 0x6000 && address & 0xE100 == 0x4100 => {
+                self.prg_bank = ((value >> 3) & 1) as usize;
+                self.chr_bank = (value & 0x07) as usize;
+            },
+            79 => {},
+            _ if address >= 0x8000 => {
+                let value = value & self.load_prg_u8(address);
+                if self.mapper_id == 11 {
+                    self.prg_bank = (value & 0x03) as usize;
+                    self.chr_bank = (value >> 4) as usize;
+                }
+                else {
+                    self.prg_bank = ((value >> 4) & 0x03) as usize;
+                    self.chr_bank = (value & 0x03) as usize;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, _address: u16, _value: u8) {
+        // CHR-ROM only
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        rom_mirroring(&self.rom)
+    }
+}
+
+// Mapper 34 covers two unrelated boards: BNROM (32K PRG latch at $8000-$FFFF, CHR-RAM)
+// and NINA-001 (registers at $7FFD-$7FFF, two 4K CHR-ROM banks, 8K PRG-RAM).
+// Without a NES 2.0 submapper, only NINA-001 has more than 8K of CHR
+struct Mapper34 {
+    rom: Box<rom::NesRom>,
+    ram: [u8; 0x2000],
+    nina: bool,
+    prg_bank: usize,
+    chr_banks: [usize; 2]
+}
+
+impl Mapper34 {
+    fn new(rom: rom::NesRom) -> Mapper34 {
+        let nina = match rom.submapper {
+            1 => true,
+            2 => false,
+            _ => rom.chr_rom.len() > 0x2000
+        };
+        let mut ram = [0; 0x2000];
+        rom.load_trainer(&mut ram);
+        Mapper34 {
+            rom: Box::new(rom),
+            ram,
+            nina,
+            prg_bank: 0,
+            chr_banks: [0, 1]
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let offset = if self.nina {
+            self.chr_banks[((address >> 12) & 1) as usize] * 0x1000 + (address & 0xFFF) as usize
+        }
+        else {
+            (address & 0x1FFF) as usize
+        };
+        offset % self.rom.chr_len()
+    }
+}
+
+impl Mapper for Mapper34 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        else if address < 0x8000 {
+            return if self.nina { self.ram[(address & 0x1FFF) as usize] } else { 0 };
+        }
+        let offset = self.prg_bank * 0x8000 + (address & 0x7FFF) as usize;
+        self.rom.prg_rom[offset % self.rom.prg_rom.len()]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr(self.chr_offset(address))
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        if self.nina {
+            if (0x6000..0x8000).contains(&address) {
+                self.ram[(address & 0x1FFF) as usize] = value;
+                match address {
+                    0x7FFD => self.prg_bank = (value & 1) as usize,
+                    0x7FFE => self.chr_banks[0] = (value & 0x0F) as usize,
+                    0x7FFF => self.chr_banks[1] = (value & 0x0F) as usize,
+                    _ => {}
+                }
+            }
+        }
+        else if address >= 0x8000 {
+            let value = value & self.load_prg_u8(address);
+            self.prg_bank = value as usize;
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        let offset = self.chr_offset(address);
+        self.rom.store_chr(offset, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        rom_mirroring(&self.rom)
+    }
+
+    fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        if self.nina { battery_ram(&self.rom, &mut self.ram) } else { None }
+    }
+}
+
+// Camerica/Codemasters (mapper 71): UxROM-like 16K PRG switching at $C000-$FFFF.
+// Fire Hawk's board also selects single-screen mirroring with bit 4 at $9000-$9FFF
+struct Mapper71 {
+    rom: Box<rom::NesRom>,
+    prg_bank: usize,
+    prg_banks: usize,
+    single_screen: Option<[u16; 4]>
+}
+
+impl Mapper71 {
+    fn new(rom: rom::NesRom) -> Mapper71 {
+        let prg_banks = bank_count(rom.prg_rom.len(), 0x4000);
+        Mapper71 {
+            rom: Box::new(rom),
+            prg_bank: 0,
+            prg_banks,
+            single_screen: None
+        }
+    }
+}
+
+impl Mapper for Mapper71 {
+    fn load_prg_u8(&mut self, address: u16) -> u8 {
+        if address < 0x8000 {
+            return 0;
+        }
+        let bank = if address < 0xC000 { self.prg_bank } else { self.prg_banks - 1 };
+        self.rom.prg_rom[(bank * 0x4000 + (address & 0x3FFF) as usize) % self.rom.prg_rom.len()]
+    }
+
+    fn load_chr_u8(&self, address: u16) -> u8 {
+        self.rom.load_chr((address & 0x1FFF) as usize)
+    }
+
+    fn store_prg_u8(&mut self, address: u16, value: u8) {
+        match address {
+            0x9000..=0x9FFF => {
+                self.single_screen = Some(if value & 0x10 != 0 {
+                    MIRRORING_SINGLE_HIGH
+                }
+                else {
+                    MIRRORING_SINGLE_LOW
+                });
+            },
+            0xC000..=0xFFFF => self.prg_bank = (value & 0x0F) as usize % self.prg_banks,
+            _ => {}
+        }
+    }
+
+    fn store_chr_u8(&mut self, address: u16, value: u8) {
+        self.rom.store_chr((address & 0x1FFF) as usize, value);
+    }
+
+    fn mirroring(&self) -> [u16; 4] {
+        // boards without the mirroring bit are hardwired like the header says
+        self.single_screen.unwrap_or_else(|| rom_mirroring(&self.rom))
+    }
+}
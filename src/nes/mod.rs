@@ -1,14 +1,19 @@
 mod cpu;
 mod bus;
-mod rom;
-mod mapper;
+pub mod rom;
+pub mod mapper;
+pub mod checksum;
+pub mod gamedb;
+pub mod patch;
+pub mod fds;
 mod ppu;
 mod apu;
-mod controller;
+pub mod controller;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::error::Error;
-use sdl2::keyboard::Keycode;
+use std::fs;
+use std::path::{Path, PathBuf};
 use sdl2::render::{WindowCanvas, Texture};
 use sdl2::surface;
 use std::time::{SystemTime, Duration, UNIX_EPOCH, Instant};
@@ -17,26 +22,132 @@ use sdl2::audio::{AudioQueue, AudioFormatNum};
 
 // NTSC frequency ~1.79 MHz
 const NES_CPU_FREQUENCY: f64 = 1.789773;
+const FDS_BIOS_FILENAME: &str = "disksys.rom";
+
+// optional inputs besides the ROM itself
+#[derive(Default)]
+pub struct LoadOptions {
+    // IPS/UPS/BPS patch, otherwise one named like the ROM is used if present
+    pub patch: Option<PathBuf>,
+    // Famicom Disk System BIOS, for .fds images
+    pub fds_bios: Option<PathBuf>,
+    // game database (gamedb.txt format or nes20db.xml) checked before the embedded one
+    pub gamedb: Option<PathBuf>
+}
 
 pub struct Nes {
     cpu: cpu::Cpu,
+    // standard pads, 1 and 2 plugged into the ports at power on, 3 and 4 used through a multitap
+    controllers: [Rc<RefCell<controller::Controller>>; 4],
     frame: u64,
-    last_frame: Instant
+    last_frame: Instant,
+    save_path: PathBuf,
+    // battery RAM as last written to disk, to only flush when it changed
+    saved_ram: Vec<u8>,
+    // battery RAM at power on, for save locations without a .sav yet
+    initial_ram: Option<Vec<u8>>
 }
 
 impl Nes {
     pub fn new(romfile: &str) -> Result<Nes, Box<dyn Error>> {
-        let mapper = mapper::from_file(romfile)?;
+        Nes::with_registry(romfile, &mapper::MapperRegistry::new())
+    }
+
+    // builds the cartridge through the given registry, for frontends bringing their own mappers
+    pub fn with_registry(romfile: &str, registry: &mapper::MapperRegistry) -> Result<Nes, Box<dyn Error>> {
+        Nes::with_options(romfile, &LoadOptions::default(), registry)
+    }
+
+    // applies the given IPS/UPS/BPS patch instead of looking for one next to the ROM
+    pub fn with_patch(romfile: &str, patchfile: &str) -> Result<Nes, Box<dyn Error>> {
+        let options = LoadOptions { patch: Some(PathBuf::from(patchfile)), ..LoadOptions::default() };
+        Nes::with_options(romfile, &options, &mapper::MapperRegistry::new())
+    }
+
+    pub fn with_options(romfile: &str, options: &LoadOptions, registry: &mapper::MapperRegistry) -> Result<Nes, Box<dyn Error>> {
+        let mut data = fs::read(romfile)?;
+        if let Some(patchfile) = options.patch.clone().or_else(|| patch::find_patch(Path::new(romfile))) {
+            let patch_data = fs::read(&patchfile)?;
+            data = patch::apply(&patch_data, &data)
+                .map_err(|e| format!("{}: {}", patchfile.display(), e))?;
+        }
+        let mapper: Rc<RefCell<dyn mapper::Mapper>> = if fds::is_fds_image(&data) {
+            // the BIOS can't be shipped, look for the usual disksys.rom next to the disk by default
+            let bios_path = options.fds_bios.clone()
+                .unwrap_or_else(|| Path::new(romfile).with_file_name(FDS_BIOS_FILENAME));
+            let bios = fs::read(&bios_path)
+                .map_err(|e| format!("FDS BIOS {}: {}", bios_path.display(), e))?;
+            Rc::new(RefCell::new(fds::Fds::new(&data, &bios)?))
+        }
+        else {
+            let gamedb = options.gamedb.as_deref().map(gamedb::GameDb::from_file).transpose()?;
+            registry.create(rom::NesRom::from_bytes_with_db(&data, gamedb.as_ref())?)?
+        };
+        let save_path = Path::new(romfile).with_extension("sav");
         let ppu = ppu::Ppu::new(Rc::clone(&mapper));
         let apu = apu::Apu::new();
         let bus = bus::Bus::new(mapper, ppu, apu);
         let cpu = cpu::Cpu::new(bus);
 
-        Ok(Nes {
+        let controllers = [
+            Rc::new(RefCell::new(controller::Controller::new())),
+            Rc::new(RefCell::new(controller::Controller::new())),
+            Rc::new(RefCell::new(controller::Controller::new())),
+            Rc::new(RefCell::new(controller::Controller::new()))
+        ];
+        let mut nes = Nes {
             cpu,
+            controllers,
             frame: 0,
-            last_frame: Instant::now()
-        })
+            last_frame: Instant::now(),
+            save_path,
+            saved_ram: Vec::new(),
+            initial_ram: None
+        };
+        for port in 0..2 {
+            let device = Rc::clone(&nes.controllers[port]);
+            nes.connect(port, Some(device));
+        }
+        nes.load_battery_ram()?;
+        Ok(nes)
+    }
+
+    // keeps .sav files in dir instead of next to the ROM, reloading battery RAM from there
+    pub fn set_save_dir(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let filename = self.save_path.file_name().ok_or("invalid save file name")?.to_owned();
+        self.save_path = dir.join(filename);
+        self.load_battery_ram()
+    }
+
+    fn load_battery_ram(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut mapper = self.cpu.bus.mapper.borrow_mut();
+        if let Some(ram) = mapper.battery_ram() {
+            // only the current save path counts, RAM read from an earlier one is dropped
+            let initial = self.initial_ram.get_or_insert_with(|| ram.to_vec());
+            ram.copy_from_slice(initial);
+            if self.save_path.exists() {
+                let data = fs::read(&self.save_path)?;
+                let len = std::cmp::min(data.len(), ram.len());
+                ram[..len].copy_from_slice(&data[..len]);
+            }
+            self.saved_ram = ram.to_vec();
+        }
+        Ok(())
+    }
+
+    // writes battery RAM to the .sav file if it changed since the last flush
+    pub fn flush_battery_ram(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut mapper = self.cpu.bus.mapper.borrow_mut();
+        if let Some(ram) = mapper.battery_ram() {
+            if ram[..] != self.saved_ram[..] {
+                // write then rename, so a crash never leaves a truncated save behind
+                let tmp_path = self.save_path.with_extension("sav.tmp");
+                fs::write(&tmp_path, &ram)?;
+                fs::rename(&tmp_path, &self.save_path)?;
+                self.saved_ram = ram.to_vec();
+            }
+        }
+        Ok(())
     }
 
     pub fn powerup(&mut self) {
@@ -48,8 +159,60 @@ impl Nes {
         self.last_frame = Instant::now();
     }
 
-    pub fn update_controller(&mut self, keycode: Keycode, pressed: bool) {
-        self.cpu.bus.controller.update(keycode, pressed);
+    // frames turbo buttons stay on and off, for both pads
+    pub fn set_turbo_rate(&mut self, frames: u32) {
+        for controller in &self.controllers {
+            controller.borrow_mut().set_turbo_rate(frames);
+        }
+    }
+
+    pub fn set_direction_policy(&mut self, policy: controller::DirectionPolicy) {
+        for controller in &self.controllers {
+            controller.borrow_mut().set_direction_policy(policy);
+        }
+    }
+
+    pub fn disk_sides(&self) -> usize {
+        self.cpu.bus.mapper.borrow().disk_sides()
+    }
+
+    pub fn inserted_disk(&self) -> Option<usize> {
+        self.cpu.bus.mapper.borrow().inserted_disk()
+    }
+
+    pub fn insert_disk(&mut self, side: Option<usize>) {
+        self.cpu.bus.mapper.borrow_mut().insert_disk(side);
+    }
+
+    // plugs a device into port 0 or 1, None leaves the port empty
+    pub fn connect(&mut self, port: usize, device: Option<Rc<RefCell<dyn controller::InputDevice>>>) {
+        self.cpu.bus.ports[port] = device;
+    }
+
+    // plugs a Zapper into port 2, returning it so the frontend can aim and fire
+    pub fn connect_zapper(&mut self) -> Rc<RefCell<controller::Zapper>> {
+        let zapper = Rc::new(RefCell::new(controller::Zapper::new()));
+        self.connect(1, Some(Rc::clone(&zapper) as Rc<RefCell<dyn controller::InputDevice>>));
+        zapper
+    }
+
+    // puts a four player adapter on both ports, pads 3 and 4 become readable
+    pub fn connect_multitap(&mut self, kind: controller::MultitapKind) {
+        let tap = Rc::new(RefCell::new(controller::Multitap::new(kind, self.controllers.clone())));
+        for port in 0..2 {
+            let device = Rc::new(RefCell::new(controller::MultitapPort::new(&tap, port)));
+            self.connect(port, Some(device));
+        }
+    }
+
+    // the standard pad for the given player, whether or not it's currently plugged in
+    pub fn controller(&self, player: usize) -> Rc<RefCell<controller::Controller>> {
+        Rc::clone(&self.controllers[player])
+    }
+
+    // button is one of the controller::CONTROLLER_* constants
+    pub fn set_button(&mut self, player: usize, button: usize, pressed: bool) {
+        self.controllers[player].borrow_mut().set_button(button, pressed);
     }
 
     pub fn set_audio_queue(&mut self, audio_queue: Rc<RefCell<AudioQueue<u8>>>) {
@@ -66,6 +229,9 @@ impl Nes {
                 self.cpu.bus.ppu.copy_frame(buffer);
             });
             self.frame += 1;
+            for controller in &self.controllers {
+                controller.borrow_mut().next_frame();
+            }
         }
     }
 }
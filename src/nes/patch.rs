@@ -0,0 +1,421 @@
+use super::checksum;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// largest output UPS/BPS patches may ask for, well above any real cartridge
+const MAX_TARGET_SIZE: usize = 64 * 1024 * 1024;
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> PatchReader<'a> {
+        PatchReader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if len > self.data.len() - self.pos {
+            return Err("patch is truncated".into());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> Result<usize, Box<dyn Error>> {
+        let b = self.bytes(2)?;
+        Ok(((b[0] as usize) << 8) | b[1] as usize)
+    }
+
+    fn u24_be(&mut self) -> Result<usize, Box<dyn Error>> {
+        let b = self.bytes(3)?;
+        Ok(((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+
+    // target size of a UPS/BPS patch, refused past MAX_TARGET_SIZE before allocating it
+    fn target_size(&mut self) -> Result<usize, Box<dyn Error>> {
+        let size = self.varint()?;
+        if size > MAX_TARGET_SIZE {
+            return Err(format!("patch target size {} is too large", size).into());
+        }
+        Ok(size)
+    }
+
+    fn u32_le(&mut self) -> Result<u32, Box<dyn Error>> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // UPS/BPS variable length numbers, 7 bits per byte with the top bit marking the last one
+    fn varint(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let x = self.u8()?;
+            value = ((x & 0x7F) as usize).checked_mul(shift)
+                .and_then(|v| value.checked_add(v))
+                .ok_or("invalid patch number")?;
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or("invalid patch number")?;
+            value = value.checked_add(shift).ok_or("invalid patch number")?;
+        }
+    }
+}
+
+// looks for a patch named like the ROM, e.g. game.ips next to game.nes
+pub fn find_patch(romfile: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter()
+        .map(|ext| romfile.with_extension(ext))
+        .find(|path| path.exists())
+}
+
+// applies an IPS, UPS or BPS patch to a whole ROM file, header included
+pub fn apply(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(patch, rom)
+    }
+    else if patch.starts_with(b"UPS1") {
+        apply_ups(patch, rom)
+    }
+    else if patch.starts_with(b"BPS1") {
+        apply_bps(patch, rom)
+    }
+    else {
+        Err("unknown patch format".into())
+    }
+}
+
+fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = rom.to_vec();
+    let mut reader = PatchReader::new(patch, 5);
+    loop {
+        let offset = reader.u24_be()?;
+        // "EOF" is also a valid offset, it's only the end marker when nothing follows
+        // but an optional truncation size
+        if offset == 0x454F46 && (reader.pos == patch.len() || reader.pos + 3 == patch.len()) {
+            if reader.pos + 3 == patch.len() {
+                let size = reader.u24_be()?;
+                out.truncate(size);
+            }
+            return Ok(out);
+        }
+
+        let size = reader.u16_be()?;
+        let (len, data) = if size == 0 {
+            // RLE record
+            let len = reader.u16_be()?;
+            (len, None)
+        }
+        else {
+            (size, Some(reader.bytes(size)?))
+        };
+
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match data {
+            Some(data) => out[offset..offset + len].copy_from_slice(data),
+            None => {
+                let value = reader.u8()?;
+                out[offset..offset + len].iter_mut().for_each(|b| *b = value);
+            }
+        }
+    }
+}
+
+// UPS and BPS end with the CRC32 of the source, target and patch itself
+fn check_footer(patch: &[u8], rom: &[u8]) -> Result<u32, Box<dyn Error>> {
+    if patch.len() < 16 {
+        return Err("patch is truncated".into());
+    }
+    let mut footer = PatchReader::new(patch, patch.len() - 12);
+    let source_crc = footer.u32_le()?;
+    let target_crc = footer.u32_le()?;
+    let patch_crc = footer.u32_le()?;
+    if checksum::crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err("patch is corrupted (checksum mismatch)".into());
+    }
+    if checksum::crc32(rom) != source_crc {
+        return Err("patch is for a different ROM (source checksum mismatch)".into());
+    }
+    Ok(target_crc)
+}
+
+fn check_target(out: &[u8], target_crc: u32) -> Result<(), Box<dyn Error>> {
+    if checksum::crc32(out) != target_crc {
+        return Err("patched ROM checksum mismatch".into());
+    }
+    Ok(())
+}
+
+fn apply_ups(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let target_crc = check_footer(patch, rom)?;
+    let mut reader = PatchReader::new(&patch[..patch.len() - 12], 4);
+    let _source_size = reader.varint()?;
+    let target_size = reader.target_size()?;
+
+    let mut out = vec![0u8; target_size];
+    let len = std::cmp::min(rom.len(), target_size);
+    out[..len].copy_from_slice(&rom[..len]);
+
+    let mut pos = 0usize;
+    while reader.pos < reader.data.len() {
+        pos = pos.checked_add(reader.varint()?).ok_or("patch writes past the end of the ROM")?;
+        // XOR the bytes in until a zero, which also counts as one byte
+        loop {
+            let x = reader.u8()?;
+            if x == 0 {
+                pos += 1;
+                break;
+            }
+            if pos >= out.len() {
+                return Err("patch writes past the end of the ROM".into());
+            }
+            out[pos] ^= x;
+            pos += 1;
+        }
+    }
+
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+// BPS copy offsets: bit 0 is the sign, the rest the distance from the last position
+fn relative_offset(position: isize, offset: usize) -> Result<isize, Box<dyn Error>> {
+    let delta = (offset >> 1) as isize;
+    let position = if offset & 1 != 0 { position.checked_sub(delta) } else { position.checked_add(delta) };
+    match position {
+        Some(position) if position >= 0 => Ok(position),
+        _ => Err("patch reads before the start of the data".into())
+    }
+}
+
+fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let target_crc = check_footer(patch, rom)?;
+    let mut reader = PatchReader::new(&patch[..patch.len() - 12], 4);
+    let _source_size = reader.varint()?;
+    let target_size = reader.target_size()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_rel = 0isize;
+    let mut target_rel = 0isize;
+    while reader.pos < reader.data.len() {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err("patch writes past the end of the ROM".into());
+        }
+        match data & 3 {
+            // source read
+            0 => {
+                let start = out.len();
+                let bytes = rom.get(start..start + len).ok_or("patch reads past the end of the ROM")?;
+                out.extend_from_slice(bytes);
+            },
+            // target read
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // source copy
+            2 => {
+                source_rel = relative_offset(source_rel, reader.varint()?)?;
+                let start = source_rel as usize;
+                let bytes = rom.get(start..start + len).ok_or("patch reads past the end of the ROM")?;
+                out.extend_from_slice(bytes);
+                source_rel += len as isize;
+            },
+            // target copy, byte by byte since the ranges can overlap
+            _ => {
+                target_rel = relative_offset(target_rel, reader.varint()?)?;
+                for _ in 0..len {
+                    let b = *out.get(target_rel as usize).ok_or("patch reads past the end of the output")?;
+                    out.push(b);
+                    target_rel += 1;
+                }
+            }
+        }
+    }
+
+    check_target(&out, target_crc)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(mut value: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(x | 0x80);
+                return out;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    // appends the source, target and patch CRC32 footer of UPS and BPS
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&checksum::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&checksum::crc32(target).to_le_bytes());
+        let crc = checksum::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    fn bps(source: &[u8], target: &[u8], commands: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(encode(source.len()));
+        patch.extend(encode(target.len()));
+        patch.extend(encode(0));
+        patch.extend_from_slice(commands);
+        with_footer(patch, source, target)
+    }
+
+    #[test]
+    fn varint_decoding() {
+        for &value in &[0, 1, 127, 128, 129, 16511, 16512, 1 << 40] {
+            let data = encode(value);
+            assert_eq!(PatchReader::new(&data, 0).varint().unwrap(), value);
+        }
+        assert_eq!(PatchReader::new(&[0x00, 0x80], 0).varint().unwrap(), 128);
+        assert!(PatchReader::new(&[0x00], 0).varint().is_err());
+        // more bits than a usize holds
+        let mut data = vec![0x7F; 12];
+        data.push(0xFF);
+        assert!(PatchReader::new(&data, 0).varint().is_err());
+    }
+
+    #[test]
+    fn ips_records() {
+        let rom = vec![0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        // RLE: 3 times 0xCC at offset 5, growing the ROM
+        patch.extend_from_slice(&[0, 0, 5, 0, 0, 0, 3, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&patch, &rom).unwrap(), vec![0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn ips_eof_offset() {
+        // a record at offset $454F46 looks like the end marker but has data after it
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0, 1, 0x42]);
+        patch.extend_from_slice(b"EOF");
+        let out = apply(&patch, &[]).unwrap();
+        assert_eq!(out.len(), 0x454F47);
+        assert_eq!(out[0x454F46], 0x42);
+
+        // the end marker followed by a truncation size
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0, 0, 2]);
+        assert_eq!(apply(&patch, &[1, 2, 3, 4]).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn ips_truncated() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 4, 0xAA]);
+        assert!(apply(&patch, &[0; 8]).is_err());
+        assert!(apply(b"PATCH\x00\x00", &[0; 8]).is_err());
+    }
+
+    #[test]
+    fn ups_xor() {
+        let source = vec![1, 2, 3, 4];
+        let target = vec![1, 7, 3, 4, 9];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(encode(source.len()));
+        patch.extend(encode(target.len()));
+        // skip 1, XOR 2^7, end of run (which takes a byte too); skip 1, XOR 0^9 past the source
+        patch.extend(encode(1));
+        patch.extend_from_slice(&[2 ^ 7, 0]);
+        patch.extend(encode(1));
+        patch.extend_from_slice(&[9, 0]);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_copies() {
+        let source = b"abcdef".to_vec();
+        let target = b"defabcXYXYXYab".to_vec();
+        let mut commands = Vec::new();
+        // SourceCopy 3 from +3, then 3 from -6 (back to the start)
+        commands.extend(encode((2 << 2) | 2));
+        commands.extend(encode(3 << 1));
+        commands.extend(encode((2 << 2) | 2));
+        commands.extend(encode((6 << 1) | 1));
+        // TargetRead "XY", TargetCopy 4 overlapping from offset 6
+        commands.extend(encode((1 << 2) | 1));
+        commands.extend_from_slice(b"XY");
+        commands.extend(encode((3 << 2) | 3));
+        commands.extend(encode(6 << 1));
+        // TargetCopy 2 from -7 relative to where the last copy ended (10 - 7 = 3)
+        commands.extend(encode((1 << 2) | 3));
+        commands.extend(encode((7 << 1) | 1));
+        let patch = bps(&source, &target, &commands);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_rejects_bad_offsets() {
+        let source = b"abcdef".to_vec();
+        let target = b"abc".to_vec();
+        // SourceCopy from -1
+        let mut commands = encode((2 << 2) | 2);
+        commands.extend(encode((1 << 1) | 1));
+        assert!(apply(&bps(&source, &target, &commands), &source).is_err());
+        // TargetCopy with nothing written yet
+        let mut commands = encode((2 << 2) | 3);
+        commands.extend(encode(0));
+        assert!(apply(&bps(&source, &target, &commands), &source).is_err());
+        // SourceRead longer than the target
+        let commands = encode(5 << 2);
+        assert!(apply(&bps(&source, &target, &commands), &source).is_err());
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let source = b"abc".to_vec();
+        // SourceRead of the 3 bytes
+        let commands = encode(2 << 2);
+        let mut patch = bps(&source, &source, &commands);
+        assert_eq!(apply(&patch, &source).unwrap(), source);
+        // wrong source ROM
+        assert!(apply(&patch, b"abd").is_err());
+        // corrupted patch
+        patch[4] ^= 1;
+        assert!(apply(&patch, &source).is_err());
+        // right patch, wrong target checksum
+        let patch = with_footer([b"BPS1".to_vec(), encode(3), encode(3), encode(0), commands].concat(), &source, b"abd");
+        assert!(apply(&patch, &source).is_err());
+    }
+
+    #[test]
+    fn target_size_cap() {
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(encode(0));
+        patch.extend(encode(MAX_TARGET_SIZE + 1));
+        let patch = with_footer(patch, &[], &[]);
+        let err = apply(&patch, &[]).expect_err("oversized target accepted");
+        assert!(err.to_string().contains("too large"));
+    }
+}
@@ -14,7 +14,8 @@ pub struct Ppu {
     ppu_status: u8,
     oam_addr: u8,
     oam: [u8; 256],
-    ram: [u8; 0x800],
+    // 2K of CIRAM, followed by the 2K four-screen cartridges add
+    ram: [u8; 0x1000],
     palette: [u8; 0x20],
     sec_oam: [u8; 32],
     sprite_count: usize,
@@ -28,7 +29,6 @@ pub struct Ppu {
     odd_frame: bool,
     framebuffer: [u8; PPU_FRAMEBUFFER_SZ],
     frame_ready: bool,
-    nt_mirror: [u16; 4],
     nt: u8,
     at: u8,
     bg_low: u8,
@@ -46,7 +46,8 @@ pub struct Ppu {
     t: u16,
     x: u8,
     w: bool,
-    frame: u128
+    frame: u128,
+    clock: u64
 }
 
 const PPUCTRL: u16 = 0x0;
@@ -147,7 +148,6 @@ static PPU_PATTERN_REVERSE: [u8; 256] = [
 
 impl Ppu {
     pub fn new(mapper: Rc<RefCell<dyn mapper::Mapper>>) -> Ppu {
-        let mirroring = mapper.borrow().mirroring();
         Ppu {
             mapper,
             ppu_ctrl: 0,
@@ -155,7 +155,7 @@ impl Ppu {
             ppu_status: 0,
             oam_addr: 0,
             oam: [0; 256],
-            ram: [0; 0x800],
+            ram: [0; 0x1000],
             palette: [0; 0x20],
             sec_oam: [0; 32],
             sprite_count: 0,
@@ -168,7 +168,6 @@ impl Ppu {
             scanline: 240,
             odd_frame: false,
             framebuffer: [0; PPU_FRAMEBUFFER_SZ],
-            nt_mirror: mirroring,
             frame_ready: false,
             tile_data: 0,
             nt: 0,
@@ -187,7 +186,8 @@ impl Ppu {
             t: 0,
             x: 0,
             w: false,
-            frame: 0
+            frame: 0,
+            clock: 0
         }
     }
 
@@ -259,26 +259,16 @@ impl Ppu {
         }
     }
 
-    fn mirror_address(&self, address: u16) -> u16 {
-        let index = address & 0x3FF;
-        if address < 0x2400 {
-            return self.nt_mirror[0] + index;
-        }
-        else if address < 0x2800 {
-            return self.nt_mirror[1] + index;
-        }
-        else if address < 0x2c00 {
-            return self.nt_mirror[2] + index;
-        }
-        else {
-            return self.nt_mirror[3] + index;
-        }
-    }
-
     fn load_chr(&self, address: u16) -> u8 {
+        self.mapper.borrow_mut().ppu_address(address, self.clock);
         self.mapper.borrow().load_chr_u8(address)
     }
 
+    fn load_nt(&self, address: u16) -> u8 {
+        self.mapper.borrow_mut().ppu_address(address, self.clock);
+        self.mapper.borrow().load_nt_u8(address, &self.ram)
+    }
+
     fn load_palette(&self, address: u8) -> u8 {
         self.palette[address as usize]
     }
@@ -289,8 +279,7 @@ impl Ppu {
             return self.load_chr(addr);
         }
         else if addr < 0x3F00 {
-            let mirrored = self.mirror_address(addr) & 0x7FF;
-            return self.ram[mirrored as usize];
+            return self.load_nt(addr);
         }
         else if addr < 0x4000 {
             return self.load_palette((addr & 0x1F) as u8);
@@ -302,12 +291,15 @@ impl Ppu {
 
     fn store_u8(&mut self, address: u16, value: u8) {
         let mut addr = address & 0x3FFF;
+        if addr < 0x3F00 {
+            self.mapper.borrow_mut().ppu_address(addr, self.clock);
+        }
+
         if addr < 0x2000 {
             self.mapper.borrow_mut().store_chr_u8(addr, value);
         }
         else if addr < 0x3F00 {
-            let mirrored = self.mirror_address(addr) & 0x7FF;
-            self.ram[mirrored as usize] = value;
+            self.mapper.borrow_mut().store_nt_u8(addr, value, &mut self.ram);
         }
         else if addr < 0x4000 {
             addr &= 0x1F;
@@ -393,16 +385,37 @@ impl Ppu {
     fn fetch_sp_tile(&self, idx: usize, low: bool) -> usize {
         let tile = self.sec_oam[idx+1];
         let mut sprow = self.scanline as u16 - self.sec_oam[idx] as u16;
-        if self.sprite_h == 8 {
+        let address = if self.sprite_h == 8 {
             if self.sp_at & OAM_SPRITE_FLIP_V != 0 {
                 sprow = 7 - sprow;
             }
-            let address = self.spr_pattern_table + tile as u16*16 + sprow as u16;
-            return self.load_chr(if low { address } else { address + 8 }) as usize;
+            self.spr_pattern_table + tile as u16*16 + sprow
         }
         else {
-            return 0;
+            // 8x16 sprites: bit 0 selects the pattern table, top and bottom tiles are consecutive
+            let table = (tile & 1) as u16 * 0x1000;
+            let mut tile = (tile & 0xFE) as u16;
+            if self.sp_at & OAM_SPRITE_FLIP_V != 0 {
+                sprow = 15 - sprow;
+            }
+            if sprow > 7 {
+                tile += 1;
+                sprow -= 8;
+            }
+            table + tile*16 + sprow
+        };
+        self.load_chr(if low { address } else { address + 8 }) as usize
+    }
+
+    fn fetch_dummy_sp_tile(&self, low: bool) {
+        // unused sprite slots still fetch tile $FF, mappers snooping A12 rely on it
+        let address = if self.sprite_h == 8 {
+            self.spr_pattern_table + 0xFF0
         }
+        else {
+            0x1FE0
+        };
+        self.load_chr(if low { address } else { address + 8 });
     }
 
     fn fetch_tile(&mut self) {
@@ -472,6 +485,10 @@ impl Ppu {
                     self.cycles += 1;
                 }
             }
+
+            if self.cycles >= 257 && self.cycles <= 320 {
+                self.sprite_fetch_cycle();
+            }
         }
     }
 
@@ -584,48 +601,7 @@ impl Ppu {
                 }
             }
             else if self.cycles >= 257 && self.cycles <= 320 {
-                let idx = ((self.cycles - 257)/8) as usize;
-                let oam_idx = idx*4;
-                if idx < self.next_sprite_count {
-                    match self.cycles % 8 {
-                        2 => {
-                            self.sp_at = self.sec_oam[oam_idx + 2];
-                        },
-                        3 => {
-                            let x = self.sec_oam[oam_idx + 3];
-                            self.sp_x[idx] = x;
-                        },
-                        5 => {
-                            self.sp_low = PPU_PATTERN_REVERSE[self.fetch_sp_tile(oam_idx, true)];
-                        },
-                        7 => {
-                            self.sp_high = PPU_PATTERN_REVERSE[self.fetch_sp_tile(oam_idx, false)];
-                        },
-                        0 => {
-                            let mut data: u32 = 0;
-                            let fliph = self.sp_at & OAM_SPRITE_FLIP_H != 0;
-                            let (mut low, mut high) = if fliph {
-                                (PPU_PATTERN_REVERSE[self.sp_low as usize],
-                                 PPU_PATTERN_REVERSE[self.sp_high as usize])
-                            }
-                            else {
-                                (self.sp_low, self.sp_high)
-                            };
-                            let at = (self.sp_at & 0b11) << 2;
-                            for i in 0..8 {
-                                let bit0 = low & 0b1;
-                                let bit1 = high & 0b1;
-                                low >>= 1;
-                                high >>= 1;
-                                let color = at | (bit1 << 1) | bit0;
-                                data |= (color as u32) << 4 * i;
-                            }
-                            self.sp_data[idx] = data;
-                            self.sp_prio[idx] = (self.sp_at >> 5) & 0b1;
-                        },
-                        _ => {}
-                    }
-                }
+                self.sprite_fetch_cycle();
                 if self.cycles == 257 {
                     self.copy_horiz();
                 }
@@ -639,6 +615,58 @@ impl Ppu {
         }
     }
 
+    fn sprite_fetch_cycle(&mut self) {
+        let idx = ((self.cycles - 257)/8) as usize;
+        let oam_idx = idx*4;
+        if idx < self.next_sprite_count {
+            match self.cycles % 8 {
+                2 => {
+                    self.sp_at = self.sec_oam[oam_idx + 2];
+                },
+                3 => {
+                    let x = self.sec_oam[oam_idx + 3];
+                    self.sp_x[idx] = x;
+                },
+                5 => {
+                    self.sp_low = PPU_PATTERN_REVERSE[self.fetch_sp_tile(oam_idx, true)];
+                },
+                7 => {
+                    self.sp_high = PPU_PATTERN_REVERSE[self.fetch_sp_tile(oam_idx, false)];
+                },
+                0 => {
+                    let mut data: u32 = 0;
+                    let fliph = self.sp_at & OAM_SPRITE_FLIP_H != 0;
+                    let (mut low, mut high) = if fliph {
+                        (PPU_PATTERN_REVERSE[self.sp_low as usize],
+                         PPU_PATTERN_REVERSE[self.sp_high as usize])
+                    }
+                    else {
+                        (self.sp_low, self.sp_high)
+                    };
+                    let at = (self.sp_at & 0b11) << 2;
+                    for i in 0..8 {
+                        let bit0 = low & 0b1;
+                        let bit1 = high & 0b1;
+                        low >>= 1;
+                        high >>= 1;
+                        let color = at | (bit1 << 1) | bit0;
+                        data |= (color as u32) << 4 * i;
+                    }
+                    self.sp_data[idx] = data;
+                    self.sp_prio[idx] = (self.sp_at >> 5) & 0b1;
+                },
+                _ => {}
+            }
+        }
+        else {
+            match self.cycles % 8 {
+                5 => self.fetch_dummy_sp_tile(true),
+                7 => self.fetch_dummy_sp_tile(false),
+                _ => {}
+            }
+        }
+    }
+
     fn vblank(&mut self) {
         if self.cycles == 1 {
             self.ppu_status |= PPU_STATUS_VBLANK;
@@ -649,6 +677,7 @@ impl Ppu {
         let mut num_cycles = num_cycles;
         while num_cycles > 0 {
             num_cycles -= 1;
+            self.clock += 1;
             match self.scanline {
                 261 => self.pre_render(),
                 0..=239 => self.visible(),
@@ -721,6 +750,16 @@ impl Ppu {
         return self.frame_ready;
     }
 
+    // the picture being drawn, BGRA, rows above the beam belong to the current frame
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    // current scanline and dot
+    pub fn beam_position(&self) -> (u32, u32) {
+        (self.scanline, self.cycles)
+    }
+
     pub fn copy_frame(&mut self, dst: &mut [u8]) {
         dst.copy_from_slice(&self.framebuffer);
         self.frame_ready = false;
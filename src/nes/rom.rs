@@ -1,83 +1,514 @@
 use std::io::prelude::*;
 use std::fs;
 use std::io;
-use std::io::SeekFrom;
 use std::error::Error;
 use byteorder::{LittleEndian, ReadBytesExt};
+use super::checksum;
+use super::gamedb;
 
 const NES_ROM_SIGNATURE: u32 = 0x1A53454E;
 const NES_ROM_MIRRORING: u8 = 1 << 0;
 const NES_ROM_HAS_RAM: u8 = 1 << 1;
 const NES_ROM_HAS_TRAINER: u8 = 1 << 2;
 const NES_ROM_IGNORE_MIRRORING: u8 = 1 << 3;
+const UNIF_HEADER_SIZE: usize = 32;
 
+// vendor prefixes of UNIF board names, e.g. NES-SLROM, HVC-UNROM, UNL-SA-NROM
+const UNIF_PREFIXES: [&str; 13] = [
+    "NES-", "HVC-", "UNL-", "BMC-", "BTL-", "IREM-", "KONAMI-", "SUNSOFT-", "AVE-", "CAMERICA-",
+    "TAITO-", "JALECO-", "NAMCOT-"
+];
+
+// UNIF board names, without the vendor prefix, and the mapper/submapper implementing them
+const UNIF_BOARDS: [(&str, u16, u8); 68] = [
+    ("NROM", 0, 0), ("NROM-128", 0, 0), ("NROM-256", 0, 0), ("RROM", 0, 0), ("RROM-128", 0, 0),
+    ("SROM", 0, 0),
+    ("SAROM", 1, 0), ("SBROM", 1, 0), ("SCROM", 1, 0), ("SEROM", 1, 0), ("SGROM", 1, 0),
+    ("SKROM", 1, 0), ("SLROM", 1, 0), ("SL1ROM", 1, 0), ("SNROM", 1, 0), ("SOROM", 1, 0),
+    ("SUROM", 1, 0),
+    ("UNROM", 2, 0), ("UOROM", 2, 0), ("CNROM", 3, 0),
+    ("TBROM", 4, 0), ("TEROM", 4, 0), ("TFROM", 4, 0), ("TGROM", 4, 0), ("TKROM", 4, 0),
+    ("TLROM", 4, 0), ("TR1ROM", 4, 0), ("TSROM", 4, 0), ("TVROM", 4, 0), ("HKROM", 4, 1),
+    ("ELROM", 5, 0), ("EKROM", 5, 0), ("ETROM", 5, 0), ("EWROM", 5, 0),
+    ("ANROM", 7, 0), ("AMROM", 7, 0), ("AOROM", 7, 0),
+    ("PNROM", 9, 0), ("PEEOROM", 9, 0), ("FJROM", 10, 0), ("FKROM", 10, 0),
+    ("COLORDREAMS-74*377", 11, 0),
+    ("VRC-4A", 21, 1), ("VRC-4C", 21, 2), ("VRC-2A", 22, 0),
+    ("VRC-4F", 23, 1), ("VRC-4E", 23, 2), ("VRC-2B", 23, 3),
+    ("VRC-4B", 25, 1), ("VRC-4D", 25, 2), ("VRC-2C", 25, 3),
+    ("BNROM", 34, 2), ("NINA-001", 34, 1), ("NINA-01", 34, 1),
+    ("GNROM", 66, 0), ("MHROM", 66, 0),
+    ("JLROM", 69, 0), ("JSROM", 69, 0), ("BTR", 69, 0), ("5B", 69, 0),
+    ("BF9093", 71, 0), ("BF909X", 71, 0), ("BF9097", 71, 1),
+    ("NINA-03", 79, 0), ("NINA-06", 79, 0), ("NINA-003", 79, 0), ("NINA-006", 79, 0), ("MB-91", 79, 0)
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Mirroring {
     Vertical,
-    Horizontal
+    Horizontal,
+    // the cartridge provides 2K of extra nametable RAM
+    FourScreen,
+    // hardwired to one nametable, only UNIF can say so
+    SingleScreenLow,
+    SingleScreenHigh
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HeaderFormat {
+    INes,
+    Nes20,
+    Unif
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    // NES 2.0 extended console type, byte 13
+    Extended(u8)
 }
 
 pub struct NesRom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    // CHR-RAM follows CHR-ROM in the CHR address space, on boards that have both
+    pub chr_ram: Vec<u8>,
+    // 512 bytes to be loaded at $7000-$71FF
+    pub trainer: Option<Vec<u8>>,
     pub mirroring: Mirroring,
     pub has_ram: bool,
-    pub mapper_id: u8
+    pub format: HeaderFormat,
+    pub mapper_id: u16,
+    pub submapper: u8,
+    // RAM sizes in bytes, the nvram ones are battery backed
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub expansion_device: u8,
+    // UNIF board name, e.g. "NES-TLROM"
+    pub board: Option<String>,
+    // checksums of PRG+CHR, without header and trainer
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+    // whether the header values come from the game database
+    pub in_database: bool
+}
+
+// NES 2.0 ROM sizes: either a multiple of the unit or, when the MSB nibble is $F,
+// 2^E * (MM*2+1) bytes from the EEEEEEMM LSB byte
+fn rom_size(lsb: u8, msb: u8, unit: usize) -> Result<usize, Box<dyn Error>> {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = ((lsb & 3) * 2 + 1) as usize;
+        2usize.checked_pow(exponent).and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| format!("invalid ROM size 2^{} * {}", exponent, multiplier).into())
+    }
+    else {
+        Ok((((msb as usize) << 8) | lsb as usize) * unit)
+    }
+}
+
+// NES 2.0 RAM sizes are shift counts: 64 << n bytes, 0 means none
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 { 0 } else { 64 << shift as usize }
 }
 
 impl NesRom {
     pub fn new(filename: &str) -> Result<NesRom, Box<dyn Error>> {
-        let f = fs::File::open(filename)?;
-        let metadata = f.metadata()?;
+        NesRom::from_bytes(&fs::read(filename)?)
+    }
+
+    // reads the whole stream, e.g. an archive entry or a socket, then parses it
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<NesRom, Box<dyn Error>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        NesRom::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<NesRom, Box<dyn Error>> {
+        NesRom::from_bytes_with_db(data, None)
+    }
+
+    // db, e.g. one imported from nes20db.xml, is consulted before the embedded database
+    pub fn from_bytes_with_db(data: &[u8], db: Option<&gamedb::GameDb>) -> Result<NesRom, Box<dyn Error>> {
+        if data.starts_with(b"UNIF") {
+            return NesRom::from_unif(data, db);
+        }
 
         // ensure this is a valid nes rom
-        if metadata.len() < 16 {
+        if data.len() < 16 {
             return Err("invalid header".into());
         }
 
-        let mut reader = io::BufReader::new(f);
+        let mut reader = io::Cursor::new(data);
         let sig = reader.read_u32::<LittleEndian>()?;
         if sig != NES_ROM_SIGNATURE {
             return Err("invalid signature".into());
         }
 
-        let mut chr_ram = false;
-
         let prg_sz = reader.read_u8()?;
-        let mut chr_sz = reader.read_u8()?;
+        let chr_sz = reader.read_u8()?;
         let fl6 = reader.read_u8()?;
         let fl7 = reader.read_u8()?;
         let fl8 = reader.read_u8()?;
         let fl9 = reader.read_u8()?;
         let fl10 = reader.read_u8()?;
-        let _ = reader.seek(SeekFrom::Current(5))?;
+        let fl11 = reader.read_u8()?;
+        let fl12 = reader.read_u8()?;
+        let fl13 = reader.read_u8()?;
+        let fl14 = reader.read_u8()?;
+        let fl15 = reader.read_u8()?;
+
+        let format = if fl7 & 0x0C == 0x08 { HeaderFormat::Nes20 } else { HeaderFormat::INes };
+        let mut mapper_id = (((fl6 & 0xF0) >> 4) | (fl7 & 0xF0)) as u16;
+        let mut submapper = 0;
+        let mut console_type = match fl7 & 0x03 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(0)
+        };
+        let prg_size;
+        let chr_size;
+        let prg_ram_size;
+        let mut prg_nvram_size = 0;
+        let mut chr_ram_size = 0;
+        let mut chr_nvram_size = 0;
+        let timing;
+        let mut expansion_device = 0;
+
+        if format == HeaderFormat::Nes20 {
+            mapper_id |= ((fl8 & 0x0F) as u16) << 8;
+            submapper = fl8 >> 4;
+            prg_size = rom_size(prg_sz, fl9 & 0x0F, 0x4000)?;
+            chr_size = rom_size(chr_sz, fl9 >> 4, 0x2000)?;
+            prg_ram_size = ram_size(fl10 & 0x0F);
+            prg_nvram_size = ram_size(fl10 >> 4);
+            chr_ram_size = ram_size(fl11 & 0x0F);
+            chr_nvram_size = ram_size(fl11 >> 4);
+            timing = match fl12 & 0x03 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy
+            };
+            if let ConsoleType::Extended(_) = console_type {
+                console_type = ConsoleType::Extended(fl13 & 0x0F);
+            }
+            expansion_device = fl15 & 0x3F;
+        }
+        else {
+            // old dumping tools left signatures like "DiskDude!" in bytes 7-15,
+            // which makes the upper mapper nibble garbage
+            if fl12 != 0 || fl13 != 0 || fl14 != 0 || fl15 != 0 {
+                mapper_id &= 0x0F;
+                console_type = ConsoleType::Nes;
+            }
+            prg_size = prg_sz as usize * 0x4000;
+            chr_size = chr_sz as usize * 0x2000;
+            // iNES 1.0 counts PRG-RAM in 8K units, 0 meaning 8K for compatibility
+            prg_ram_size = std::cmp::max(fl8 as usize, 1) * 0x2000;
+            timing = if fl9 & 1 != 0 { Timing::Pal } else { Timing::Ntsc };
+        }
+
+        if prg_size == 0 {
+            return Err("invalid header: no PRG-ROM".into());
+        }
+        let trainer_size = if fl6 & NES_ROM_HAS_TRAINER != 0 { 512 } else { 0 };
+        let expected = prg_size.checked_add(chr_size).and_then(|size| size.checked_add(16 + trainer_size))
+            .ok_or("invalid header: ROM sizes overflow")?;
+        if data.len() < expected {
+            return Err(format!("truncated ROM: header declares {} bytes, file has {}", expected, data.len()).into());
+        }
 
-        if chr_sz == 0 {
-            chr_ram = true;
-            chr_sz = 1;
+        let trainer = if fl6 & NES_ROM_HAS_TRAINER != 0 {
+            let mut trainer = vec![0u8; 512];
+            reader.read_exact(&mut trainer)?;
+            Some(trainer)
         }
-        let mut prg_rom = vec![0u8; prg_sz as usize * 16384];
-        let mut chr_rom = vec![0u8; chr_sz as usize * 8192];
+        else {
+            None
+        };
+
+        let mut prg_rom = vec![0u8; prg_size];
         reader.read_exact(&mut prg_rom)?;
 
-        if !chr_ram {
-            reader.read_exact(&mut chr_rom)?;
+        let mut chr_rom = vec![0u8; chr_size];
+        reader.read_exact(&mut chr_rom)?;
+
+        // iNES 1.0 can't describe CHR-RAM, no CHR-ROM means an 8K CHR-RAM board
+        let mut chr_ram_len = chr_ram_size + chr_nvram_size;
+        if chr_ram_len == 0 && chr_size == 0 {
+            chr_ram_len = 0x2000;
         }
+        let chr_ram = vec![0u8; chr_ram_len];
 
-        let mirroring = if fl6 & NES_ROM_MIRRORING != 0 {
+        let mirroring = if fl6 & NES_ROM_IGNORE_MIRRORING != 0 {
+            Mirroring::FourScreen
+        }
+        else if fl6 & NES_ROM_MIRRORING != 0 {
             Mirroring::Vertical
         }
         else {
             Mirroring::Horizontal
         };
         let has_ram = fl6 & NES_ROM_HAS_RAM != 0;
-        let mapper_id = ((fl6 & 0xF0) >> 4) | (fl7 & 0xF0);
 
-        Ok(NesRom {
+        let mut rom = NesRom {
             prg_rom,
             chr_rom,
+            chr_ram,
+            trainer,
             mirroring,
             has_ram,
-            mapper_id
-        })
+            format,
+            mapper_id,
+            submapper,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            timing,
+            console_type,
+            expansion_device,
+            board: None,
+            crc32: 0,
+            sha1: [0; 20],
+            in_database: false
+        };
+        rom.identify(db);
+        Ok(rom)
     }
-}
\ No newline at end of file
+
+    fn from_unif(data: &[u8], db: Option<&gamedb::GameDb>) -> Result<NesRom, Box<dyn Error>> {
+        if data.len() < UNIF_HEADER_SIZE {
+            return Err("invalid UNIF header".into());
+        }
+
+        let mut board = None;
+        let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut mirroring = Mirroring::Horizontal;
+        let mut has_ram = false;
+
+        let mut pos = UNIF_HEADER_SIZE;
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let len = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+            pos += 8;
+            if pos + len > data.len() {
+                return Err(format!("truncated UNIF chunk {}", String::from_utf8_lossy(id)).into());
+            }
+            let chunk = &data[pos..pos + len];
+            pos += len;
+
+            // PRG0..PRGF and CHR0..CHRF are numbered in hex
+            let index = (id[3] as char).to_digit(16).map(|i| i as usize);
+            match (&id[..3], index) {
+                (b"PRG", Some(i)) => prg_chunks[i] = Some(chunk),
+                (b"CHR", Some(i)) => chr_chunks[i] = Some(chunk),
+                _ => match id {
+                    b"MAPR" => {
+                        let name = chunk.split(|&b| b == 0).next().unwrap_or(&[]);
+                        board = Some(String::from_utf8_lossy(name).into_owned());
+                    },
+                    b"MIRR" => {
+                        // 5 is mapper controlled, the mapper sets it itself
+                        mirroring = match chunk.first() {
+                            Some(1) => Mirroring::Vertical,
+                            Some(2) => Mirroring::SingleScreenLow,
+                            Some(3) => Mirroring::SingleScreenHigh,
+                            Some(4) => Mirroring::FourScreen,
+                            _ => Mirroring::Horizontal
+                        };
+                    },
+                    b"BATR" => has_ram = true,
+                    _ => {}
+                }
+            }
+        }
+
+        let board = board.ok_or("UNIF file has no MAPR chunk")?;
+        let name = UNIF_PREFIXES.iter()
+            .find_map(|prefix| board.strip_prefix(prefix))
+            .unwrap_or(&board);
+        let &(_, mapper_id, submapper) = UNIF_BOARDS.iter()
+            .find(|&&(b, _, _)| b == name || b == board)
+            .ok_or_else(|| format!("unsupported UNIF board {}", board))?;
+
+        let prg_rom: Vec<u8> = prg_chunks.iter().flatten().flat_map(|c| c.iter().copied()).collect();
+        let chr_rom: Vec<u8> = chr_chunks.iter().flatten().flat_map(|c| c.iter().copied()).collect();
+        if prg_rom.is_empty() {
+            return Err("UNIF file has no PRG chunks".into());
+        }
+        if !prg_rom.len().is_multiple_of(0x4000) {
+            return Err(format!("UNIF PRG-ROM size {} is not a multiple of 16K", prg_rom.len()).into());
+        }
+        let chr_ram_size = if chr_rom.is_empty() { 0x2000 } else { 0 };
+
+        let mut rom = NesRom {
+            prg_rom,
+            chr_rom,
+            chr_ram: vec![0u8; chr_ram_size],
+            trainer: None,
+            mirroring,
+            has_ram,
+            format: HeaderFormat::Unif,
+            mapper_id,
+            submapper,
+            prg_ram_size: if has_ram { 0 } else { 0x2000 },
+            prg_nvram_size: if has_ram { 0x2000 } else { 0 },
+            chr_ram_size,
+            chr_nvram_size: 0,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            expansion_device: 0,
+            board: Some(board),
+            crc32: 0,
+            sha1: [0; 20],
+            in_database: false
+        };
+        rom.identify(db);
+        Ok(rom)
+    }
+
+    // checksums PRG+CHR and applies the game database entry, if any
+    fn identify(&mut self, db: Option<&gamedb::GameDb>) {
+        let mut crc = checksum::Crc32::new();
+        let mut sha1 = checksum::Sha1::new();
+        for data in &[&self.prg_rom, &self.chr_rom] {
+            crc.update(data);
+            sha1.update(data);
+        }
+        self.crc32 = crc.finish();
+        self.sha1 = sha1.finish();
+        let game = db.and_then(|db| db.lookup(self.crc32, &self.sha1))
+            .or_else(|| gamedb::GameDb::embedded().lookup(self.crc32, &self.sha1));
+        if let Some(game) = game {
+            self.apply_game_info(game);
+        }
+    }
+
+    // replaces the header values with the ones from a database entry
+    pub fn apply_game_info(&mut self, game: &gamedb::GameInfo) {
+        self.mapper_id = game.mapper_id;
+        self.submapper = game.submapper;
+        self.mirroring = game.mirroring;
+        self.prg_ram_size = game.prg_ram_size;
+        self.prg_nvram_size = game.prg_nvram_size;
+        self.chr_ram_size = game.chr_ram_size;
+        self.chr_nvram_size = game.chr_nvram_size;
+        self.timing = game.timing;
+        self.has_ram = game.prg_nvram_size > 0 || game.chr_nvram_size > 0;
+        let chr_ram_len = game.chr_ram_size + game.chr_nvram_size;
+        if chr_ram_len > 0 || !self.chr_rom.is_empty() {
+            self.chr_ram = vec![0u8; chr_ram_len];
+        }
+        self.in_database = true;
+    }
+
+    // copies the trainer, if any, where it goes in the $6000-$7FFF PRG-RAM window
+    pub fn load_trainer(&self, ram: &mut [u8]) {
+        if let Some(trainer) = &self.trainer {
+            if ram.len() >= 0x1200 {
+                ram[0x1000..0x1200].copy_from_slice(trainer);
+            }
+        }
+    }
+
+    // size of the whole CHR address space, ROM then RAM
+    pub fn chr_len(&self) -> usize {
+        self.chr_rom.len() + self.chr_ram.len()
+    }
+
+    // offsets wrap around the CHR address space, so boards with less than 8K of
+    // CHR-ROM or CHR-RAM mirror it
+    pub fn load_chr(&self, offset: usize) -> u8 {
+        let offset = offset % self.chr_len();
+        if offset < self.chr_rom.len() {
+            self.chr_rom[offset]
+        }
+        else {
+            self.chr_ram[offset - self.chr_rom.len()]
+        }
+    }
+
+    // writes to CHR-ROM are ignored
+    pub fn store_chr(&mut self, offset: usize, value: u8) {
+        let offset = offset % self.chr_len();
+        if offset >= self.chr_rom.len() {
+            self.chr_ram[offset - self.chr_rom.len()] = value;
+        }
+    }
+
+    // whether PRG-RAM keeps its contents across power cycles
+    pub fn has_battery(&self) -> bool {
+        self.has_ram || self.prg_nvram_size > 0
+    }
+
+    // PRG-RAM size from the header when it has one, default otherwise
+    pub fn prg_ram_len(&self, default: usize) -> usize {
+        let size = self.prg_ram_size + self.prg_nvram_size;
+        let exact = self.format == HeaderFormat::Nes20 || self.in_database;
+        if exact && size > 0 { size } else { default }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ines_header(prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+        vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, chr_banks, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    }
+
+    #[test]
+    fn rejects_empty_prg_rom() {
+        let mut data = ines_header(0, 1);
+        data.extend(vec![0u8; 0x2000]);
+        let err = NesRom::from_bytes(&data).err().expect("header without PRG-ROM accepted");
+        assert!(err.to_string().contains("no PRG-ROM"));
+    }
+
+    #[test]
+    fn rejects_truncated_rom() {
+        let mut data = ines_header(2, 1);
+        data.extend(vec![0u8; 0x4000]);
+        assert!(NesRom::from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn small_chr_rom_is_mirrored() {
+        // NES 2.0, 16K PRG-ROM and 4K (2^12 * 1) CHR-ROM
+        let mut data = ines_header(1, 12 << 2);
+        data[7] = 0x08;
+        data[9] = 0xF0;
+        data.extend(vec![0u8; 0x4000]);
+        data.extend((0..0x1000).map(|i| i as u8));
+        let rom = NesRom::from_bytes(&data).unwrap();
+        assert_eq!(rom.chr_len(), 0x1000);
+        assert_eq!(rom.load_chr(0x1005), rom.load_chr(0x0005));
+    }
+
+    #[test]
+    fn accepts_nrom() {
+        let mut data = ines_header(1, 1);
+        data.extend(vec![0u8; 0x6000]);
+        let rom = NesRom::from_bytes(&data).unwrap();
+        assert_eq!(rom.prg_rom.len(), 0x4000);
+        assert_eq!(rom.chr_rom.len(), 0x2000);
+    }
+}